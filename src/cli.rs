@@ -17,4 +17,28 @@ pub struct CLIArgs {
         required = false
     )]
     pub config: Option<String>,
+    #[arg(
+        long,
+        help = "Check the config file, print the resolved settings (without secrets), and exit",
+        required = false
+    )]
+    pub check_config: bool,
+    #[arg(
+        long = "set",
+        value_name = "SECTION.KEY=VALUE",
+        value_parser = parse_override,
+        help = "Override a setting from the config file, like network.port=8080 (may be repeated)",
+        required = false
+    )]
+    pub set: Vec<(String, String)>,
+}
+
+/// Split a `--set` argument into a setting name and value.
+fn parse_override(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.to_owned()))
+        }
+        _ => Err(format!("expected SECTION.KEY=VALUE, got '{s}'")),
+    }
 }
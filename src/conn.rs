@@ -1,5 +1,8 @@
 //! Client connection state
 use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 use tracing::{debug, trace};
 use uuid::Uuid;
@@ -12,9 +15,12 @@ use crate::event::Event;
 use crate::subscription::Subscription;
 use crate::utils::{host_str, unix_time};
 
-/// A subscription identifier has a maximum length
+/// Default maximum length of a subscription identifier
 const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
 
+/// Default maximum concurrent subscriptions
+const MAX_SUBSCRIPTIONS: usize = 32;
+
 /// NIP-42 authentication state
 pub enum Nip42AuthState {
     /// The client is not authenticated yet
@@ -35,6 +41,10 @@ pub struct ClientConn {
     subscriptions: HashMap<String, Subscription>,
     /// Per-connection maximum concurrent subscriptions
     max_subs: usize,
+    /// Maximum length of a subscription identifier
+    max_subid_len: usize,
+    /// Per-connection limit of in-flight database queries (if any)
+    db_queries: Option<Arc<Semaphore>>,
     /// NIP-42 AUTH
     auth: Nip42AuthState,
 }
@@ -54,11 +64,50 @@ impl ClientConn {
             client_ip_addr,
             client_id,
             subscriptions: HashMap::new(),
-            max_subs: 32,
+            max_subs: MAX_SUBSCRIPTIONS,
+            max_subid_len: MAX_SUBSCRIPTION_ID_LEN,
+            db_queries: None,
             auth: NoAuth,
         }
     }
 
+    /// Limit the number of concurrent subscriptions for this connection.
+    pub fn set_max_subs(&mut self, max: usize) {
+        self.max_subs = max;
+    }
+
+    /// Limit the length of subscription identifiers for this connection.
+    pub fn set_max_subid_len(&mut self, max: usize) {
+        self.max_subid_len = max;
+    }
+
+    /// Limit the number of database queries this connection may have
+    /// in-flight at once.  A limit of zero is unlimited.
+    pub fn set_max_db_queries(&mut self, max: u32) {
+        self.db_queries = if max > 0 {
+            Some(Arc::new(Semaphore::new(max as usize)))
+        } else {
+            None
+        };
+    }
+
+    /// Reserve capacity for a new database query.  The query may run
+    /// until the returned permit (if any) is dropped.
+    /// # Errors
+    ///
+    /// Will return `Err` if the client already has the maximum number
+    /// of database queries in-flight.
+    pub fn reserve_db_query(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        match &self.db_queries {
+            Some(sem) => sem
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| Error::DbQueryMaxExceededError),
+            None => Ok(None),
+        }
+    }
+
     #[must_use]
     pub fn subscriptions(&self) -> &HashMap<String, Subscription> {
         &self.subscriptions
@@ -70,6 +119,12 @@ impl ClientConn {
         self.subscriptions.values().any(|x| x == sub)
     }
 
+    /// Get the client's unique identifier.
+    #[must_use]
+    pub fn get_client_id(&self) -> String {
+        self.client_id.to_string()
+    }
+
     /// Get a short prefix of the client's unique identifier, suitable
     /// for logging.
     #[must_use]
@@ -108,7 +163,7 @@ impl ClientConn {
         let sub_id_len = k.len();
         // prevent arbitrarily long subscription identifiers from
         // being used.
-        if sub_id_len > MAX_SUBSCRIPTION_ID_LEN {
+        if sub_id_len > self.max_subid_len {
             debug!(
                 "ignoring sub request with excessive length: ({})",
                 sub_id_len
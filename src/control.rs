@@ -0,0 +1,242 @@
+//! Control plane for driving a running relay
+//!
+//! A relay started with [`RelayHandle::start`] runs on its own thread,
+//! and is managed by sending [`ControlMessage`]s through the handle.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::server::start_server;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::mpsc as syncmpsc;
+use std::sync::mpsc::Sender as MpscSender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use tokio::sync::{broadcast, oneshot};
+
+/// Commands accepted by a running relay.
+#[derive(Debug)]
+pub enum ControlMessage {
+    /// Stop accepting connections, disconnect clients and exit.
+    Shutdown,
+    /// Replace the settings used for new connections, and rebuild
+    /// the relay management lists from them.
+    ReloadSettings(Box<Settings>),
+    /// Ban a pubkey, hiding any events it has published.
+    BanPubkey {
+        pubkey: String,
+        reason: Option<String>,
+    },
+    /// Disconnect a client by its id (as shown in stats).
+    DisconnectClient(String),
+    /// Send relay statistics to the provided channel.
+    DumpStats(oneshot::Sender<RelayStats>),
+    /// Checkpoint the database write-ahead log.
+    Checkpoint,
+    /// Vacuum the database.
+    Vacuum,
+}
+
+/// A connected client.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientStats {
+    /// Client id; logs show its first 8 characters
+    pub client_id: String,
+    /// Remote IP address
+    pub remote_ip: String,
+    /// User agent, if one was sent
+    pub user_agent: Option<String>,
+    /// When the client connected (unix time)
+    pub connected_at: u64,
+}
+
+/// A snapshot of relay activity.
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayStats {
+    /// Currently connected clients
+    pub clients: Vec<ClientStats>,
+    /// Websocket connections since startup
+    pub connections_total: u64,
+    /// `EVENT` commands received since startup
+    pub events_received: u64,
+    /// Events written to the database since startup
+    pub events_written: u64,
+    /// `REQ` commands received since startup
+    pub subscriptions_received: u64,
+}
+
+/// Connected clients, and a way to disconnect them.
+#[derive(Clone)]
+pub struct ClientRegistry {
+    clients: Arc<Mutex<HashMap<String, ClientStats>>>,
+    disconnect_tx: broadcast::Sender<String>,
+}
+
+impl Default for ClientRegistry {
+    fn default() -> Self {
+        let (disconnect_tx, _) = broadcast::channel(64);
+        ClientRegistry {
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            disconnect_tx,
+        }
+    }
+}
+
+impl ClientRegistry {
+    /// Track a new client.  The returned channel receives the ids
+    /// of clients that should be disconnected.
+    pub fn register(&self, client: ClientStats) -> broadcast::Receiver<String> {
+        let rx = self.disconnect_tx.subscribe();
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client.client_id.clone(), client);
+        rx
+    }
+
+    /// Stop tracking a client.
+    pub fn unregister(&self, client_id: &str) {
+        self.clients.lock().unwrap().remove(client_id);
+    }
+
+    /// Ask a client to disconnect.  Returns false if the client is
+    /// not connected.
+    pub fn disconnect(&self, client_id: &str) -> bool {
+        if !self.clients.lock().unwrap().contains_key(client_id) {
+            return false;
+        }
+        self.disconnect_tx.send(client_id.to_owned()).is_ok()
+    }
+
+    /// Currently connected clients, oldest first.
+    #[must_use]
+    pub fn clients(&self) -> Vec<ClientStats> {
+        let mut clients: Vec<ClientStats> =
+            self.clients.lock().unwrap().values().cloned().collect();
+        clients.sort_by(|a, b| (a.connected_at, &a.client_id).cmp(&(b.connected_at, &b.client_id)));
+        clients
+    }
+}
+
+/// Handle to a relay running on a background thread.
+pub struct RelayHandle {
+    ctrl_tx: MpscSender<ControlMessage>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl RelayHandle {
+    /// Start a relay on a new thread, returning immediately.
+    #[must_use]
+    pub fn start(settings: &Settings) -> RelayHandle {
+        let settings = settings.clone();
+        let (ctrl_tx, ctrl_rx) = syncmpsc::channel();
+        let thread = thread::spawn(move || start_server(&settings, ctrl_rx));
+        RelayHandle { ctrl_tx, thread }
+    }
+
+    /// A sender for control messages, for use from other threads.
+    #[must_use]
+    pub fn sender(&self) -> MpscSender<ControlMessage> {
+        self.ctrl_tx.clone()
+    }
+
+    /// Send a control message to the relay.
+    pub fn send(&self, msg: ControlMessage) -> Result<()> {
+        self.ctrl_tx.send(msg).map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Request a graceful shutdown.
+    pub fn shutdown(&self) -> Result<()> {
+        self.send(ControlMessage::Shutdown)
+    }
+
+    /// Apply new settings to new connections and the management lists.
+    pub fn reload_settings(&self, settings: Settings) -> Result<()> {
+        self.send(ControlMessage::ReloadSettings(Box::new(settings)))
+    }
+
+    /// Ban a pubkey (hex), hiding any events it has published.
+    pub fn ban_pubkey(&self, pubkey: &str, reason: Option<&str>) -> Result<()> {
+        self.send(ControlMessage::BanPubkey {
+            pubkey: pubkey.to_owned(),
+            reason: reason.map(ToOwned::to_owned),
+        })
+    }
+
+    /// Disconnect a client by id.
+    pub fn disconnect_client(&self, client_id: &str) -> Result<()> {
+        self.send(ControlMessage::DisconnectClient(client_id.to_owned()))
+    }
+
+    /// Get a snapshot of relay activity.
+    pub async fn stats(&self) -> Result<RelayStats> {
+        let (tx, rx) = oneshot::channel();
+        self.send(ControlMessage::DumpStats(tx))?;
+        rx.await.map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Checkpoint the database write-ahead log.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.send(ControlMessage::Checkpoint)
+    }
+
+    /// Vacuum the database.
+    pub fn vacuum(&self) -> Result<()> {
+        self.send(ControlMessage::Vacuum)
+    }
+
+    /// Block until the relay has stopped.
+    pub fn join(self) -> Result<()> {
+        self.thread.join().map_err(|_| Error::JoinError)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(id: &str, connected_at: u64) -> ClientStats {
+        ClientStats {
+            client_id: id.to_owned(),
+            remote_ip: "127.0.0.1".to_owned(),
+            user_agent: None,
+            connected_at,
+        }
+    }
+
+    #[test]
+    fn registry_tracks_clients() {
+        let registry = ClientRegistry::default();
+        let _rx_b = registry.register(client("b", 2));
+        let _rx_a = registry.register(client("a", 1));
+        let ids: Vec<String> = registry
+            .clients()
+            .into_iter()
+            .map(|c| c.client_id)
+            .collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        registry.unregister("a");
+        assert_eq!(registry.clients().len(), 1);
+    }
+
+    #[test]
+    fn clients_sharing_a_prefix_are_distinct() {
+        let registry = ClientRegistry::default();
+        let mut rx_a = registry.register(client("0123abcd-0001", 1));
+        let _rx_b = registry.register(client("0123abcd-0002", 2));
+        assert_eq!(registry.clients().len(), 2);
+        assert!(registry.disconnect("0123abcd-0002"));
+        assert_eq!(rx_a.try_recv().unwrap(), "0123abcd-0002");
+        registry.unregister("0123abcd-0002");
+        assert!(registry.disconnect("0123abcd-0001"));
+    }
+
+    #[test]
+    fn disconnect_known_client() {
+        let registry = ClientRegistry::default();
+        let mut rx = registry.register(client("a", 1));
+        assert!(!registry.disconnect("b"));
+        assert!(registry.disconnect("a"));
+        assert_eq!(rx.try_recv().unwrap(), "a");
+    }
+}
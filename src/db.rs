@@ -1,15 +1,21 @@
 //! Event persistence and querying
 use crate::config::Settings;
+use crate::delegation::DelegationRevocations;
 use crate::error::{Error, Result};
 use crate::event::Event;
+use crate::management::SharedPolicy;
 use crate::nauthz;
-use crate::notice::Notice;
+use crate::notice::{EventResultStatus, Notice};
 use crate::payment::PaymentMessage;
 use crate::repo::postgres::{PostgresPool, PostgresRepo};
 use crate::repo::sqlite::SqliteRepo;
 use crate::repo::NostrRepo;
 use crate::server::NostrMetrics;
-use governor::clock::Clock;
+use crate::utils::is_lower_hex;
+use governor::clock::DefaultClock;
+use governor::nanos::Nanos;
+use governor::state::keyed::{DefaultKeyedStateStore, ShrinkableKeyedStateStore};
+use governor::state::StateStore;
 use governor::{Quota, RateLimiter};
 use log::LevelFilter;
 use nostr::key::FromPkStr;
@@ -18,8 +24,10 @@ use r2d2;
 use sqlx::pool::PoolOptions;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::ConnectOptions;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroU32;
 use std::sync::Arc;
-use std::thread;
 use std::time::{Duration, Instant};
 use tracing::{debug, info, trace, warn};
 
@@ -88,7 +96,7 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
         None => pool.clone(),
     };
 
-    let repo = PostgresRepo::new(pool, write_pool, metrics);
+    let repo = PostgresRepo::new(pool, write_pool, settings, metrics);
 
     // Panic on migration failure
     let version = repo.migrate_up().await.unwrap();
@@ -99,41 +107,65 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
 }
 
 /// Spawn a database writer that persists events to the `SQLite` store.
+#[allow(clippy::too_many_arguments)]
 pub async fn db_writer(
     repo: Arc<dyn NostrRepo>,
-    settings: Settings,
+    mut settings_rx: tokio::sync::watch::Receiver<Settings>,
     mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
     bcast_tx: tokio::sync::broadcast::Sender<Event>,
     metadata_tx: tokio::sync::broadcast::Sender<Event>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
+    policy: SharedPolicy,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<()> {
+    let mut settings = settings_rx.borrow_and_update().clone();
     // are we performing NIP-05 checking?
-    let nip05_active = settings.verified_users.is_active();
+    let mut nip05_active = settings.verified_users.is_active();
     // are we requriing NIP-05 user verification?
-    let nip05_enabled = settings.verified_users.is_enabled();
+    let mut nip05_enabled = settings.verified_users.is_enabled();
 
     let pay_to_relay_enabled = settings.pay_to_relay.enabled;
-    let cost_per_event = settings.pay_to_relay.cost_per_event;
+    let mut cost_per_event = settings.pay_to_relay.cost_per_event;
     debug!("Pay to relay: {}", pay_to_relay_enabled);
 
     //upgrade_db(&mut pool.get()?)?;
 
-    // Make a copy of the whitelist
-    let whitelist = &settings.authorization.pubkey_whitelist.clone();
+    // cache all revocations for checking new events, then apply
+    // any new delegations revoked by the operator.
+    let mut revocations = DelegationRevocations::default();
+    for (delegator, delegatee) in repo.get_delegation_revocations().await? {
+        revocations.insert(&delegator, &delegatee);
+    }
+    apply_revoked_delegations(&repo, &settings, &mut revocations).await?;
+    // pubkeys that requested to vanish (NIP-62), and when.
+    let mut vanished: HashMap<String, u64> =
+        repo.get_vanish_requests().await?.into_iter().collect();
+    let mut relay_url = settings.info.relay_url.clone();
 
     // get rate limit settings
-    let rps_setting = settings.limits.messages_per_sec;
     let mut most_recent_rate_limit = Instant::now();
-    let mut lim_opt = None;
-    let clock = governor::clock::QuantaClock::default();
-    if let Some(rps) = rps_setting {
-        if rps > 0 {
-            info!("Enabling rate limits for event creation ({}/sec)", rps);
-            let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
-            lim_opt = Some(RateLimiter::direct(Quota::per_minute(quota)));
-        }
-    }
+    let mut lim_opt = event_quota(settings.limits.messages_per_sec).map(|q| {
+        info!("Enabling rate limits for event creation ({:?})", q);
+        RateLimiter::direct(q)
+    });
+    // keyed rate limits, to stop one client from starving the others
+    let mut pubkey_lim_opt: Option<KeyedLimiter<String>> =
+        event_quota(settings.limits.messages_per_sec_per_pubkey).map(|q| {
+            info!("Enabling per-pubkey rate limits for event creation ({:?})", q);
+            KeyedLimiter::new(q)
+        });
+    let mut ip_lim_opt: Option<KeyedLimiter<String>> =
+        event_quota(settings.limits.messages_per_sec_per_ip).map(|q| {
+            info!("Enabling per-IP rate limits for event creation ({:?})", q);
+            KeyedLimiter::new(q)
+        });
+    let mut auth_lim_opt: Option<KeyedLimiter<Vec<u8>>> =
+        event_quota(settings.limits.messages_per_sec_per_auth).map(|q| {
+            info!("Enabling per-auth-pubkey rate limits for event creation ({:?})", q);
+            KeyedLimiter::new(q)
+        });
+    // keyed limiters grow with every new key; forget idle keys periodically
+    let mut last_limiter_cleanup = Instant::now();
     // create a client if GRPC is enabled.
     // Check with externalized event admitter service, if one is defined.
     let mut grpc_client = if let Some(svr) = settings.grpc.event_admission_server {
@@ -151,91 +183,258 @@ pub async fn db_writer(
             info!("shutting down database writer");
             break;
         }
-        // call blocking read on channel
-        let next_event = event_rx.recv().await;
+        // wait for the next event, applying reloaded settings as
+        // soon as they arrive.  Rate limiters are only replaced if
+        // their limit changed, so clients keep their budgets.
+        let next_event = tokio::select! {
+            next_event = event_rx.recv() => next_event,
+            Ok(()) = settings_rx.changed() => {
+                let new_settings = settings_rx.borrow_and_update().clone();
+                let (old, new) = (&settings.limits, &new_settings.limits);
+                if new.messages_per_sec != old.messages_per_sec {
+                    lim_opt = event_quota(new.messages_per_sec).map(RateLimiter::direct);
+                }
+                if new.messages_per_sec_per_pubkey != old.messages_per_sec_per_pubkey {
+                    pubkey_lim_opt =
+                        event_quota(new.messages_per_sec_per_pubkey).map(KeyedLimiter::new);
+                }
+                if new.messages_per_sec_per_ip != old.messages_per_sec_per_ip {
+                    ip_lim_opt = event_quota(new.messages_per_sec_per_ip).map(KeyedLimiter::new);
+                }
+                if new.messages_per_sec_per_auth != old.messages_per_sec_per_auth {
+                    auth_lim_opt = event_quota(new.messages_per_sec_per_auth).map(KeyedLimiter::new);
+                }
+                settings = new_settings;
+                nip05_active = settings.verified_users.is_active();
+                nip05_enabled = settings.verified_users.is_enabled();
+                cost_per_event = settings.pay_to_relay.cost_per_event;
+                relay_url = settings.info.relay_url.clone();
+                if let Err(e) = apply_revoked_delegations(&repo, &settings, &mut revocations).await {
+                    warn!("could not apply revoked delegations: {:?}", e);
+                }
+                info!("database writer is using reloaded settings");
+                continue;
+            }
+        };
         // if the channel has closed, we will never get work
         if next_event.is_none() {
             break;
         }
         // track if an event write occurred; this is used to
-        // update the rate limiter
+        // charge the author for the event
         let mut event_write = false;
         let subm_event = next_event.unwrap();
-        let event = subm_event.event;
+        let mut event = subm_event.event;
         let notice_tx = subm_event.notice_tx;
+        // attribute the event to its delegator, unless revoked
+        event.update_delegation(&revocations);
+
+        // refuse events from before the author vanished
+        if vanished
+            .get(&event.pubkey)
+            .is_some_and(|t| event.created_at <= *t)
+        {
+            debug!(
+                "rejecting event: {}, author has vanished",
+                &event.get_event_id_prefix()
+            );
+            notice_tx
+                .try_send(Notice::blocked(
+                    event.id,
+                    "pubkey has requested to vanish from this relay",
+                ))
+                .ok();
+            continue;
+        }
+
+        // Check the relay management lists.  The lock is released
+        // before any other work is done.
+        let (ip_blocked, pubkey_banned, event_banned, kind_allowed, pubkey_allowed) = {
+            let p = policy.read().unwrap();
+            (
+                p.is_ip_blocked(&subm_event.source_ip),
+                p.is_pubkey_banned(&event),
+                p.is_event_banned(&event.id),
+                p.is_kind_allowed(event.kind),
+                p.is_pubkey_allowed(&event),
+            )
+        };
+        if ip_blocked || pubkey_banned || event_banned {
+            debug!(
+                "rejecting event: {}, banned by relay admin",
+                &event.get_event_id_prefix()
+            );
+            let msg = if ip_blocked {
+                "IP address is blocked by relay"
+            } else if pubkey_banned {
+                "pubkey is banned from this relay"
+            } else {
+                "event is banned by relay"
+            };
+            notice_tx.try_send(Notice::blocked(event.id, msg)).ok();
+            continue;
+        }
+
+        // Check that event kind is allowed
+        if !kind_allowed {
+            debug!(
+                "rejecting event: {}, blocked kind: {}",
+                &event.get_event_id_prefix(),
+                &event.kind
+            );
+            notice_tx
+                .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
+                .ok();
+            continue;
+        }
 
-        // Check that event kind isn't blacklisted
-        let kinds_blacklist = &settings.limits.event_kind_blacklist.clone();
-        if let Some(event_kind_blacklist) = kinds_blacklist {
-            if event_kind_blacklist.contains(&event.kind) {
+        // Check that the event has enough proof-of-work (NIP-13)
+        if let Some(min_pow) = settings.limits.min_pow_for_kind(event.kind) {
+            let difficulty = event.pow_difficulty();
+            if difficulty < min_pow {
                 debug!(
-                    "rejecting event: {}, blacklisted kind: {}",
+                    "rejecting event: {}, insufficient pow: {} < {}",
                     &event.get_event_id_prefix(),
-                    &event.kind
+                    difficulty,
+                    min_pow
                 );
                 notice_tx
-                    .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
+                    .try_send(Notice::blocked(
+                        event.id,
+                        &format!("pow: difficulty {difficulty} is less than {min_pow}"),
+                    ))
                     .ok();
                 continue;
             }
-        }
-
-        // Check that event kind isn't allowlisted
-        let kinds_allowlist = &settings.limits.event_kind_allowlist.clone();
-        if let Some(event_kind_allowlist) = kinds_allowlist {
-            if !event_kind_allowlist.contains(&event.kind) {
+            if settings.limits.pow_require_commitment
+                && event.pow_commitment().unwrap_or(0) < min_pow
+            {
                 debug!(
-                    "rejecting event: {}, allowlist kind: {}",
+                    "rejecting event: {}, pow commitment below {}",
                     &event.get_event_id_prefix(),
-                    &event.kind
+                    min_pow
                 );
                 notice_tx
-                    .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
+                    .try_send(Notice::blocked(
+                        event.id,
+                        &format!(
+                            "pow: nonce tag must commit to a difficulty of at least {min_pow}"
+                        ),
+                    ))
                     .ok();
                 continue;
             }
         }
 
+        // Check rate limits before doing any expensive work.  Events
+        // over a limit are rejected, rather than delaying the writer
+        // for every client.
+        if last_limiter_cleanup.elapsed() > Duration::from_secs(60) {
+            if let Some(ref lim) = pubkey_lim_opt {
+                lim.forget_idle();
+            }
+            if let Some(ref lim) = ip_lim_opt {
+                lim.forget_idle();
+            }
+            if let Some(ref lim) = auth_lim_opt {
+                lim.forget_idle();
+            }
+            last_limiter_cleanup = Instant::now();
+        }
+        let rate_limit_reason = if pubkey_lim_opt
+            .as_ref()
+            .is_some_and(|lim| !lim.allows(&event.pubkey))
+        {
+            Some("pubkey")
+        } else if ip_lim_opt
+            .as_ref()
+            .is_some_and(|lim| !lim.allows(&subm_event.source_ip))
+        {
+            Some("ip")
+        } else if auth_lim_opt
+            .as_ref()
+            .zip(subm_event.auth_pubkey.as_ref())
+            .is_some_and(|(lim, auth)| !lim.allows(auth))
+        {
+            Some("auth")
+        } else if lim_opt.as_ref().is_some_and(|lim| lim.check().is_err()) {
+            // checked last, since this uses up quota
+            Some("relay")
+        } else {
+            None
+        };
+        if let Some(reason) = rate_limit_reason {
+            // print out a message only once every 10 seconds.
+            if most_recent_rate_limit.elapsed().as_secs() > 10 {
+                warn!(
+                    "rate limit ({}) reached for event creation (suppressing future messages for 10 seconds)",
+                    reason
+                );
+                // reset last rate limit message
+                most_recent_rate_limit = Instant::now();
+            }
+            debug!(
+                "rejecting event: {}, rate limit ({}) from: {:?}",
+                event.get_event_id_prefix(),
+                reason,
+                subm_event.source_ip
+            );
+            notice_tx
+                .try_send(Notice::rate_limited(
+                    event.id,
+                    "slow down, too many events published",
+                ))
+                .ok();
+            continue;
+        }
+        // only use up quota once every limit allows the event
+        if let Some(ref lim) = pubkey_lim_opt {
+            lim.charge(&event.pubkey);
+        }
+        if let Some(ref lim) = ip_lim_opt {
+            lim.charge(&subm_event.source_ip);
+        }
+        if let Some((lim, auth)) = auth_lim_opt.as_ref().zip(subm_event.auth_pubkey.as_ref()) {
+            lim.charge(auth);
+        }
+
         // Set to none until balance is got from db
         // Will stay none if user in whitelisted and does not have to pay to post
         // When pay to relay is enabled the whitelist is not a list of who can post
         // It is a list of who can post for free
         let mut user_balance: Option<u64> = None;
+        // Delegated events are paid for by the delegator.
+        let payer = event.accountable_pubkey().to_owned();
         if !pay_to_relay_enabled {
-            // check if this event is authorized.
-            if let Some(allowed_addrs) = whitelist {
-                // TODO: incorporate delegated pubkeys
-                // if the event address is not in allowed_addrs.
-                if !allowed_addrs.contains(&event.pubkey) {
-                    debug!(
-                        "rejecting event: {}, unauthorized author",
-                        event.get_event_id_prefix()
-                    );
-                    notice_tx
-                        .try_send(Notice::blocked(
-                            event.id,
-                            "pubkey is not allowed to publish to this relay",
-                        ))
-                        .ok();
-                    continue;
-                }
+            // check if this event is authorized; with an allowlist,
+            // the event author or its delegator must be on it.
+            if pubkey_allowed == Some(false) {
+                debug!(
+                    "rejecting event: {}, unauthorized author",
+                    event.get_event_id_prefix()
+                );
+                notice_tx
+                    .try_send(Notice::blocked(
+                        event.id,
+                        "pubkey is not allowed to publish to this relay",
+                    ))
+                    .ok();
+                continue;
             }
         } else {
             // If the user is on whitelist there is no need to check if the user is admitted or has balance to post
-            if whitelist.is_none()
-                || (whitelist.is_some() && !whitelist.as_ref().unwrap().contains(&event.pubkey))
-            {
-                let key = Keys::from_pk_str(&event.pubkey).unwrap();
+            if pubkey_allowed != Some(true) {
+                let key = Keys::from_pk_str(&payer).unwrap();
                 match repo.get_account_balance(&key).await {
                     Ok((user_admitted, balance)) => {
                         // Checks to make sure user is admitted
                         if !user_admitted {
-                            debug!("user: {}, is not admitted", &event.pubkey);
+                            debug!("user: {}, is not admitted", &payer);
 
                             // If the user is in DB but not admitted
                             // Send meeage to payment thread to check if outstanding invoice has been paid
                             payment_tx
-                                .send(PaymentMessage::CheckAccount(event.pubkey))
+                                .send(PaymentMessage::CheckAccount(payer))
                                 .ok();
                             notice_tx
                                 .try_send(Notice::blocked(event.id, "User is not admitted"))
@@ -246,7 +445,7 @@ pub async fn db_writer(
                         // Checks that user has enough balance to post
                         // TODO: this should send an invoice to user to top up
                         if balance < cost_per_event {
-                            debug!("user: {}, does not have a balance", &event.pubkey,);
+                            debug!("user: {}, does not have a balance", &payer,);
                             notice_tx
                                 .try_send(Notice::blocked(event.id, "Insufficient balance"))
                                 .ok();
@@ -263,7 +462,7 @@ pub async fn db_writer(
                         info!("Unregistered user");
                         if settings.pay_to_relay.sign_ups && settings.pay_to_relay.direct_message {
                             payment_tx
-                                .send(PaymentMessage::NewAccount(event.pubkey))
+                                .send(PaymentMessage::NewAccount(payer))
                                 .ok();
                         }
                         let msg = "Pubkey not registered";
@@ -292,7 +491,10 @@ pub async fn db_writer(
 
         // get a validation result for use in verification and GPRC
         let validation = if nip05_active {
-            Some(repo.get_latest_user_verification(&event.pubkey).await)
+            Some(
+                repo.get_latest_user_verification(event.accountable_pubkey())
+                    .await,
+            )
         } else {
             None
         };
@@ -420,6 +622,44 @@ pub async fn db_writer(
                             subm_event.source_ip,
                         );
                         event_write = true;
+                        // revoke delegations named by the author
+                        if settings.authorization.delegation_revocation_kind == Some(event.kind) {
+                            for delegatee in event.tag_values_by_name("p") {
+                                if delegatee.len() != 64 || !is_lower_hex(&delegatee) {
+                                    continue;
+                                }
+                                match repo.revoke_delegation(&event.pubkey, &delegatee).await {
+                                    Ok(hidden) => {
+                                        info!(
+                                            "revoked delegation from {:?} to {:?} (hid {} events)",
+                                            event.get_author_prefix(),
+                                            delegatee,
+                                            hidden
+                                        );
+                                        revocations.insert(&event.pubkey, &delegatee);
+                                    }
+                                    Err(err) => {
+                                        warn!("delegation revocation failed: {:?}", err);
+                                    }
+                                }
+                            }
+                        }
+                        // delete everything from an author that vanished
+                        if event.is_vanish_request(relay_url.as_deref()) {
+                            match repo.vanish(&event.pubkey, event.created_at).await {
+                                Ok(deleted) => {
+                                    info!(
+                                        "author {:?} vanished (deleted {} events)",
+                                        event.get_author_prefix(),
+                                        deleted
+                                    );
+                                    vanished.insert(event.pubkey.clone(), event.created_at);
+                                }
+                                Err(err) => {
+                                    warn!("vanish request failed: {:?}", err);
+                                }
+                            }
+                        }
                         // send this out to all clients
                         bcast_tx.send(event.clone()).ok();
                         notice_tx.try_send(Notice::saved(event.id)).ok();
@@ -433,7 +673,7 @@ pub async fn db_writer(
             }
         }
 
-        // use rate limit, if defined, and if an event was actually written.
+        // charge for the event, if one was actually written.
         if event_write {
             // If pay to relay is diabaled or the cost per event is 0
             // No need to update user balance
@@ -441,41 +681,185 @@ pub async fn db_writer(
                 // If the user balance is some, user was not on whitelist
                 // Their balance should be reduced by the cost per event
                 if let Some(_balance) = user_balance {
-                    let pubkey = Keys::from_pk_str(&event.pubkey)?;
+                    let pubkey = Keys::from_pk_str(&payer)?;
                     repo.update_account_balance(&pubkey, false, cost_per_event)
                         .await?;
                 }
             }
-            if let Some(ref lim) = lim_opt {
-                if let Err(n) = lim.check() {
-                    let wait_for = n.wait_time_from(clock.now());
-                    // check if we have recently logged rate
-                    // limits, but print out a message only once
-                    // per second.
-                    if most_recent_rate_limit.elapsed().as_secs() > 10 {
-                        warn!(
-                            "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
-                            wait_for
-                        );
-                        // reset last rate limit message
-                        most_recent_rate_limit = Instant::now();
-                    }
-                    // block event writes, allowing them to queue up
-                    thread::sleep(wait_for);
-                    continue;
-                }
-            }
         }
     }
     info!("database connection closed");
     Ok(())
 }
 
+/// Apply delegations revoked by the operator that are not already
+/// revoked, hiding the events published by their delegatees.
+async fn apply_revoked_delegations(
+    repo: &Arc<dyn NostrRepo>,
+    settings: &Settings,
+    revocations: &mut DelegationRevocations,
+) -> Result<()> {
+    for r in settings.authorization.revoked_delegations.iter().flatten() {
+        if revocations.is_revoked(&r.delegator, &r.delegatee) {
+            continue;
+        }
+        let hidden = repo.revoke_delegation(&r.delegator, &r.delegatee).await?;
+        if hidden > 0 {
+            info!(
+                "hid {} events from revoked delegatee {:?}",
+                hidden, r.delegatee
+            );
+        }
+        revocations.insert(&r.delegator, &r.delegatee);
+    }
+    Ok(())
+}
+
+/// Rate limiter for event creation, keyed by client attribute.  A
+/// key can be checked without using up any of its quota, so that an
+/// event rejected by one limiter is not charged by the others.
+struct KeyedLimiter<K: Hash + Eq + Clone> {
+    limiter: RateLimiter<(K, bool), PeekableStateStore<K>, DefaultClock>,
+}
+
+impl<K: Hash + Eq + Clone> KeyedLimiter<K> {
+    fn new(quota: Quota) -> Self {
+        let state = PeekableStateStore(DefaultKeyedStateStore::default());
+        KeyedLimiter {
+            limiter: RateLimiter::new(quota, state, &DefaultClock::default()),
+        }
+    }
+
+    /// Does this key have quota left?  None of it is used.
+    fn allows(&self, key: &K) -> bool {
+        self.limiter.check_key(&(key.clone(), false)).is_ok()
+    }
+
+    /// Use up quota for this key.
+    fn charge(&self, key: &K) {
+        self.limiter.check_key(&(key.clone(), true)).ok();
+    }
+
+    /// Forget keys that have not been used recently.
+    fn forget_idle(&self) {
+        self.limiter.retain_recent();
+        self.limiter.shrink_to_fit();
+    }
+}
+
+/// Keyed limiter state, which is only updated for keys paired with
+/// `true`.
+struct PeekableStateStore<K: Hash + Eq + Clone>(DefaultKeyedStateStore<K>);
+
+impl<K: Hash + Eq + Clone> StateStore for PeekableStateStore<K> {
+    type Key = (K, bool);
+
+    fn measure_and_replace<T, F, E>(&self, (key, update): &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        if *update {
+            return self.0.measure_and_replace(key, f);
+        }
+        // failing every measurement leaves the stored state alone
+        self.0
+            .measure_and_replace(key, |prev| Err::<((), Nanos), _>(f(prev).map(|(t, _)| t)))
+            .unwrap_err()
+    }
+}
+
+impl<K: Hash + Eq + Clone> ShrinkableKeyedStateStore<(K, bool)> for PeekableStateStore<K> {
+    fn retain_recent(&self, drop_below: Nanos) {
+        ShrinkableKeyedStateStore::retain_recent(&self.0, drop_below);
+    }
+
+    fn shrink_to_fit(&self) {
+        ShrinkableKeyedStateStore::shrink_to_fit(&self.0);
+    }
+
+    fn len(&self) -> usize {
+        ShrinkableKeyedStateStore::len(&self.0)
+    }
+
+    fn is_empty(&self) -> bool {
+        ShrinkableKeyedStateStore::is_empty(&self.0)
+    }
+}
+
+/// Quota for an events-per-second limit, averaged over one minute.
+/// Unset or zero limits are disabled.
+fn event_quota(rps: Option<u32>) -> Option<Quota> {
+    rps.and_then(|r| NonZeroU32::new(r.saturating_mul(60)))
+        .map(Quota::per_minute)
+}
+
 /// Serialized event associated with a specific subscription request.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct QueryResult {
     /// Subscription identifier
     pub sub_id: String,
+    /// Query that produced the result, among those started by the
+    /// same connection
+    pub query_id: u64,
     /// Serialized event
     pub event: String,
+    /// Reason the relay ended the subscription, if it did
+    pub closed: Option<String>,
+}
+
+impl QueryResult {
+    /// A serialized event matching the subscription.
+    #[must_use]
+    pub fn event(sub_id: String, query_id: u64, event: String) -> Self {
+        QueryResult {
+            sub_id,
+            query_id,
+            event,
+            closed: None,
+        }
+    }
+
+    /// All stored events have been sent.
+    #[must_use]
+    pub fn eose(sub_id: String, query_id: u64) -> Self {
+        QueryResult::event(sub_id, query_id, "EOSE".to_string())
+    }
+
+    /// The relay stopped the query before completion.  The client is
+    /// sent a CLOSED message with a machine-readable prefix.
+    #[must_use]
+    pub fn closed(sub_id: String, query_id: u64, status: EventResultStatus, msg: &str) -> Self {
+        QueryResult {
+            sub_id,
+            query_id,
+            event: String::new(),
+            closed: Some(format!("{}: {}", status.prefix(), msg)),
+        }
+    }
+}
+
+/// Number of events matching a specific count request (NIP-45).
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CountResult {
+    /// Subscription identifier
+    pub sub_id: String,
+    /// Matching event count
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_limit_checked_without_charging() {
+        let lim = KeyedLimiter::new(Quota::per_minute(NonZeroU32::new(1).unwrap()));
+        let key = "abc".to_owned();
+        // checking alone never uses the quota
+        assert!(lim.allows(&key));
+        assert!(lim.allows(&key));
+        lim.charge(&key);
+        assert!(!lim.allows(&key));
+        assert!(lim.allows(&"def".to_owned()));
+    }
 }
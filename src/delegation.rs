@@ -7,6 +7,7 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::str::FromStr;
 use tracing::{debug, info};
 
@@ -101,6 +102,28 @@ impl ConditionQuery {
     }
 }
 
+/// Delegations that have been revoked by their delegator or the relay
+/// operator, keyed by (delegator, delegatee) pubkeys.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct DelegationRevocations {
+    revoked: HashSet<(String, String)>,
+}
+
+impl DelegationRevocations {
+    /// Record a revoked delegation.
+    pub fn insert(&mut self, delegator: &str, delegatee: &str) {
+        self.revoked
+            .insert((delegator.to_owned(), delegatee.to_owned()));
+    }
+
+    /// Has the delegator revoked this delegatee?
+    #[must_use]
+    pub fn is_revoked(&self, delegator: &str, delegatee: &str) -> bool {
+        self.revoked
+            .contains(&(delegator.to_owned(), delegatee.to_owned()))
+    }
+}
+
 // Verify that the delegator approved the delegation; return a ConditionQuery if so.
 #[must_use]
 pub fn validate_delegation(
@@ -113,7 +136,8 @@ pub fn validate_delegation(
     let tok = format!("nostr:delegation:{delegatee}:{cond_query}");
     // form SHA256 hash
     let digest: sha256::Hash = sha256::Hash::hash(tok.as_bytes());
-    let sig = schnorr::Signature::from_str(sigstr).unwrap();
+    // a malformed signature is not a valid delegation
+    let sig = schnorr::Signature::from_str(sigstr).ok()?;
     if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
         if let Ok(pubkey) = XOnlyPublicKey::from_str(delegator) {
             let verify = SECP.verify_schnorr(&sig, &msg, &pubkey);
@@ -33,6 +33,12 @@ pub enum Error {
     SubIdMaxLengthError,
     #[error("Maximum concurrent subscription count reached")]
     SubMaxExceededError,
+    #[error("Maximum filter count exceeded")]
+    FilterMaxExceededError,
+    #[error("Maximum values in a filter exceeded")]
+    FilterItemsMaxExceededError,
+    #[error("Maximum concurrent database query count reached")]
+    DbQueryMaxExceededError,
     // this should be used if the JSON is invalid
     #[error("JSON parsing failed")]
     JsonParseFailed(serde_json::Error),
@@ -43,7 +49,7 @@ pub enum Error {
     #[error("SQL error")]
     SqlError(rusqlite::Error),
     #[error("Config error")]
-    ConfigError(config::ConfigError),
+    ConfigError(crate::config::ConfigError),
     #[error("Data directory does not exist")]
     DatabaseDirError,
     #[error("Database Connection Pool Error")]
@@ -82,6 +88,8 @@ pub enum Error {
     URLParseError(url::ParseError),
     #[error("HTTP error")]
     HTTPError(http::Error),
+    #[error("TLS error: {0}")]
+    TlsError(String),
     #[error("Unknown/Undocumented")]
     UnknownError,
 }
@@ -147,6 +155,13 @@ impl From<WsError> for Error {
 impl From<config::ConfigError> for Error {
     /// Wrap Config error
     fn from(r: config::ConfigError) -> Self {
+        Error::ConfigError(r.into())
+    }
+}
+
+impl From<crate::config::ConfigError> for Error {
+    /// Wrap settings validation error
+    fn from(r: crate::config::ConfigError) -> Self {
         Error::ConfigError(r)
     }
 }
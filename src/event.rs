@@ -1,5 +1,5 @@
 //! Event parsing and validation
-use crate::delegation::validate_delegation;
+use crate::delegation::{validate_delegation, DelegationRevocations};
 use crate::error::Error::{
     CommandUnknownError, EventCouldNotCanonicalize, EventInvalidId, EventInvalidSignature,
     EventMalformedPubkey,
@@ -8,7 +8,7 @@ use crate::error::Result;
 use crate::event::EventWrapper::WrappedAuth;
 use crate::event::EventWrapper::WrappedEvent;
 use crate::nip05;
-use crate::utils::unix_time;
+use crate::utils::{host_str, unix_time};
 use bitcoin_hashes::{sha256, Hash};
 use lazy_static::lazy_static;
 use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
@@ -55,7 +55,7 @@ pub struct Event {
     pub sig: String,
     // Optimization for tag search, built on demand.
     #[serde(skip)]
-    pub tagidx: Option<HashMap<char, HashSet<String>>>,
+    pub tagidx: Option<HashMap<String, HashSet<String>>>,
 }
 
 /// Simple tag type for array of array of strings.
@@ -103,7 +103,6 @@ impl From<EventCmd> for Result<EventWrapper> {
             ec.event.validate().map(|_| {
                 let mut e = ec.event;
                 e.build_index();
-                e.update_delegation();
                 WrappedEvent(e)
             })
         } else if ec.cmd == "AUTH" {
@@ -116,6 +115,17 @@ impl From<EventCmd> for Result<EventWrapper> {
     }
 }
 
+/// Events of this kind replace earlier ones from the same author.
+fn is_replaceable_kind(kind: u64) -> bool {
+    kind == 0 || kind == 3 || kind == 41 || (10000..20000).contains(&kind)
+}
+
+/// Events of this kind replace earlier ones from the same author with
+/// the same `d` tag.
+fn is_param_replaceable_kind(kind: u64) -> bool {
+    (30000..40000).contains(&kind)
+}
+
 impl Event {
     #[cfg(test)]
     #[must_use]
@@ -171,16 +181,13 @@ impl Event {
     /// Should this event be replaced with newer timestamps from same author?
     #[must_use]
     pub fn is_replaceable(&self) -> bool {
-        self.kind == 0
-            || self.kind == 3
-            || self.kind == 41
-            || (self.kind >= 10000 && self.kind < 20000)
+        is_replaceable_kind(self.kind)
     }
 
     /// Should this event be replaced with newer timestamps from same author, for distinct `d` tag values?
     #[must_use]
     pub fn is_param_replaceable(&self) -> bool {
-        self.kind >= 30000 && self.kind < 40000
+        is_param_replaceable_kind(self.kind)
     }
 
     /// Should this event be replaced with newer timestamps from same author, for distinct `d` tag values?
@@ -206,6 +213,69 @@ impl Event {
         }
     }
 
+    /// Address of a replaceable or parameterized replaceable event,
+    /// as referenced by `a` tags (`<kind>:<pubkey>:<d-tag>`).
+    #[must_use]
+    pub fn address(&self) -> Option<String> {
+        if self.is_replaceable() {
+            Some(format!("{}:{}:", self.kind, self.pubkey))
+        } else {
+            self.distinct_param()
+                .map(|d| format!("{}:{}:{}", self.kind, self.pubkey, d))
+        }
+    }
+
+    /// Addresses deleted by this event (NIP-09), as `(kind, d-tag)`
+    /// pairs.  The `d` tag is only present for parameterized
+    /// replaceable kinds.  Only addresses of events from the same
+    /// author, with an addressable kind, are returned.
+    #[must_use]
+    pub fn deleted_addresses(&self) -> Vec<(u64, Option<String>)> {
+        if self.kind != 5 {
+            return vec![];
+        }
+        self.tag_values_by_name("a")
+            .iter()
+            .filter_map(|a| {
+                let mut parts = a.splitn(3, ':');
+                let kind = parts.next()?.parse::<u64>().ok()?;
+                if parts.next()? != self.pubkey {
+                    return None;
+                }
+                let d = parts.next().unwrap_or_default();
+                if is_replaceable_kind(kind) {
+                    Some((kind, None))
+                } else if is_param_replaceable_kind(kind) {
+                    Some((kind, Some(d.to_owned())))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Is this a protected event (NIP-70), which may only be published
+    /// by its author?
+    #[must_use]
+    pub fn is_protected(&self) -> bool {
+        self.tags
+            .iter()
+            .any(|t| t.first().is_some_and(|n| n == "-"))
+    }
+
+    /// Is this a request to vanish (NIP-62) from the relay at this
+    /// URL, or from all relays?
+    #[must_use]
+    pub fn is_vanish_request(&self, relay_url: Option<&str>) -> bool {
+        if self.kind != 62 {
+            return false;
+        }
+        let our_relay = relay_url.and_then(host_str);
+        self.tag_values_by_name("relay")
+            .iter()
+            .any(|r| r == "ALL_RELAYS" || (our_relay.is_some() && host_str(r) == our_relay))
+    }
+
     /// Pull a NIP-05 Name out of the event, if one exists
     #[must_use]
     pub fn get_nip05_addr(&self) -> Option<nip05::Nip05Name> {
@@ -224,9 +294,10 @@ impl Event {
 
     // is this event delegated (properly)?
     // does the signature match, and are conditions valid?
+    // and has the delegator not revoked it?
     // if so, return an alternate author for the event
     #[must_use]
-    pub fn delegated_author(&self) -> Option<String> {
+    pub fn delegated_author(&self, revocations: &DelegationRevocations) -> Option<String> {
         // is there a delegation tag?
         let delegation_tag: Vec<String> = self
             .tags
@@ -246,6 +317,11 @@ impl Event {
         let querystr: &str = delegation_tag.get(2)?;
         let sig: &str = delegation_tag.get(3)?;
 
+        if revocations.is_revoked(delegator, delegatee) {
+            debug!("event used a revoked delegation");
+            return None;
+        }
+
         // attempt to get a condition query; this requires the delegation to have a valid signature.
         if let Some(cond_query) = validate_delegation(delegator, delegatee, querystr, sig) {
             // The signature was valid, now we ensure the delegation
@@ -263,9 +339,26 @@ impl Event {
         }
     }
 
+    /// Pubkey accountable for this event: the delegator of a
+    /// delegated event (NIP-26), otherwise the author.
+    #[must_use]
+    pub fn accountable_pubkey(&self) -> &str {
+        self.delegated_by.as_deref().unwrap_or(&self.pubkey)
+    }
+
+    /// Is the author, or the delegator, one of these pubkeys?
+    #[must_use]
+    pub fn is_published_by_any(&self, pubkeys: &[String]) -> bool {
+        pubkeys.contains(&self.pubkey)
+            || self
+                .delegated_by
+                .as_ref()
+                .is_some_and(|d| pubkeys.contains(d))
+    }
+
     /// Update delegation status
-    pub fn update_delegation(&mut self) {
-        self.delegated_by = self.delegated_author();
+    pub fn update_delegation(&mut self, revocations: &DelegationRevocations) {
+        self.delegated_by = self.delegated_author(revocations);
     }
     /// Build an event tag index
     pub fn build_index(&mut self) {
@@ -274,21 +367,20 @@ impl Event {
             return;
         }
         // otherwise, build an index
-        let mut idx: HashMap<char, HashSet<String>> = HashMap::new();
-        // iterate over tags that have at least 2 elements
+        let mut idx: HashMap<String, HashSet<String>> = HashMap::new();
+        // iterate over tags that have at least 2 elements.  All tag
+        // names are indexed; subscriptions are restricted to the
+        // tag names the relay has chosen to index.
         for t in self.tags.iter().filter(|x| x.len() > 1) {
             let tagname = t.first().unwrap();
-            let tagnamechar_opt = single_char_tagname(tagname);
-            if tagnamechar_opt.is_none() {
+            if tagname.is_empty() {
                 continue;
             }
-            let tagnamechar = tagnamechar_opt.unwrap();
             let tagval = t.get(1).unwrap();
-            // ensure a vector exists for this tag
-            idx.entry(tagnamechar).or_default();
             // get the tag vec and insert entry
-            let idx_tag_vec = idx.get_mut(&tagnamechar).expect("could not get tag vector");
-            idx_tag_vec.insert(tagval.clone());
+            idx.entry(tagname.clone())
+                .or_default()
+                .insert(tagval.clone());
         }
         // save the tag structure
         self.tagidx = Some(idx);
@@ -332,6 +424,52 @@ impl Event {
         true
     }
 
+    /// Proof-of-work difficulty of this event (NIP-13), the number of
+    /// leading zero bits in the event id.
+    #[must_use]
+    pub fn pow_difficulty(&self) -> u8 {
+        let mut zeros: u8 = 0;
+        for c in self.id.chars() {
+            match c.to_digit(16) {
+                Some(0) => zeros += 4,
+                Some(n) => {
+                    zeros += n.leading_zeros() as u8 - 28;
+                    break;
+                }
+                None => break,
+            }
+        }
+        zeros
+    }
+
+    /// Target difficulty committed to in the nonce tag (NIP-13), if
+    /// one is present.
+    #[must_use]
+    pub fn pow_commitment(&self) -> Option<u8> {
+        self.tags
+            .iter()
+            .find(|t| t.first().is_some_and(|n| n == "nonce"))
+            .and_then(|t| t.get(2))
+            .and_then(|d| d.parse::<u8>().ok())
+    }
+
+    /// Check if this event was created further in the past than
+    /// allowed.
+    #[must_use]
+    pub fn is_too_old(&self, reject_past_seconds: Option<usize>) -> bool {
+        if let Some(allowable_past) = reject_past_seconds {
+            let curr_time = unix_time();
+            if self.created_at + (allowable_past as u64) < curr_time {
+                debug!(
+                    "event is too far in the past ({} seconds), rejecting",
+                    curr_time - self.created_at
+                );
+                return true;
+            }
+        }
+        false
+    }
+
     /// Check if this event has a valid signature.
     pub fn validate(&self) -> Result<()> {
         // TODO: return a Result with a reason for invalid events
@@ -409,10 +547,10 @@ impl Event {
 
     /// Determine if the given tag and value set intersect with tags in this event.
     #[must_use]
-    pub fn generic_tag_val_intersect(&self, tagname: char, check: &HashSet<String>) -> bool {
+    pub fn generic_tag_val_intersect(&self, tagname: &str, check: &HashSet<String>) -> bool {
         match &self.tagidx {
             // check if this is indexable tagname
-            Some(idx) => match idx.get(&tagname) {
+            Some(idx) => match idx.get(tagname) {
                 Some(valset) => {
                     let common = valset.intersection(check);
                     common.count() > 0
@@ -464,7 +602,7 @@ mod tests {
     fn empty_event_tag_match() {
         let event = Event::simple_event();
         assert!(!event
-            .generic_tag_val_intersect('e', &HashSet::from(["foo".to_owned(), "bar".to_owned()])));
+            .generic_tag_val_intersect("e", &HashSet::from(["foo".to_owned(), "bar".to_owned()])));
     }
 
     #[test]
@@ -474,12 +612,168 @@ mod tests {
         event.build_index();
         assert!(
             event.generic_tag_val_intersect(
-                'e',
+                "e",
                 &HashSet::from(["foo".to_owned(), "bar".to_owned()])
             )
         );
     }
 
+    #[test]
+    fn multichar_event_tag_match() {
+        let mut event = Event::simple_event();
+        event.tags = vec![vec!["lot".to_owned(), "A-1042".to_owned()]];
+        event.build_index();
+        assert!(event.generic_tag_val_intersect("lot", &HashSet::from(["A-1042".to_owned()])));
+        assert!(!event.generic_tag_val_intersect("l", &HashSet::from(["A-1042".to_owned()])));
+    }
+
+    #[test]
+    fn delegated_event_accountability() {
+        let mut event = Event::simple_event();
+        event.pubkey = "aaaa".to_owned();
+        assert_eq!(event.accountable_pubkey(), "aaaa");
+        assert!(!event.is_published_by_any(&["bbbb".to_owned()]));
+        event.delegated_by = Some("bbbb".to_owned());
+        assert_eq!(event.accountable_pubkey(), "bbbb");
+        assert!(event.is_published_by_any(&["bbbb".to_owned()]));
+        assert!(event.is_published_by_any(&["aaaa".to_owned()]));
+    }
+
+    #[test]
+    fn revoked_delegation_ignored() {
+        let secp = Secp256k1::new();
+        let delegator = secp256k1::KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let delegatee = secp256k1::KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let delegator_pk = XOnlyPublicKey::from_keypair(&delegator).to_string();
+        let delegatee_pk = XOnlyPublicKey::from_keypair(&delegatee).to_string();
+        let token = format!("nostr:delegation:{delegatee_pk}:kind=1");
+        let digest: sha256::Hash = sha256::Hash::hash(token.as_bytes());
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        let sig = secp.sign_schnorr(&msg, &delegator);
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        event.pubkey = delegatee_pk.clone();
+        event.tags = vec![vec![
+            "delegation".to_owned(),
+            delegator_pk.clone(),
+            "kind=1".to_owned(),
+            sig.to_string(),
+        ]];
+        let mut revocations = DelegationRevocations::default();
+        assert_eq!(
+            event.delegated_author(&revocations),
+            Some(delegator_pk.clone())
+        );
+        revocations.insert(&delegator_pk, &delegatee_pk);
+        assert_eq!(event.delegated_author(&revocations), None);
+    }
+
+    #[test]
+    fn deleted_address_parse() {
+        let mut event = Event::simple_event();
+        event.kind = 5;
+        event.pubkey = "aaaa".to_owned();
+        event.tags = vec![
+            vec!["a".to_owned(), "30023:aaaa:spec-1".to_owned()],
+            vec!["a".to_owned(), "30023:aaaa:a:b".to_owned()],
+            vec!["a".to_owned(), "10002:aaaa:ignored".to_owned()],
+            // another author's events cannot be deleted
+            vec!["a".to_owned(), "30023:bbbb:spec-1".to_owned()],
+            // nor events that are not addressable
+            vec!["a".to_owned(), "1:aaaa:".to_owned()],
+            vec!["a".to_owned(), "malformed".to_owned()],
+        ];
+        assert_eq!(
+            event.deleted_addresses(),
+            vec![
+                (30023, Some("spec-1".to_owned())),
+                (30023, Some("a:b".to_owned())),
+                (10002, None),
+            ]
+        );
+        event.kind = 1;
+        assert!(event.deleted_addresses().is_empty());
+    }
+
+    #[test]
+    fn event_address() {
+        let mut event = Event::simple_event();
+        event.pubkey = "aaaa".to_owned();
+        event.kind = 1;
+        assert_eq!(event.address(), None);
+        event.kind = 10002;
+        assert_eq!(event.address(), Some("10002:aaaa:".to_owned()));
+        event.kind = 30023;
+        assert_eq!(event.address(), Some("30023:aaaa:".to_owned()));
+        event.tags = vec![vec!["d".to_owned(), "spec-1".to_owned()]];
+        assert_eq!(event.address(), Some("30023:aaaa:spec-1".to_owned()));
+    }
+
+    #[test]
+    fn protected_event() {
+        let mut event = Event::simple_event();
+        assert!(!event.is_protected());
+        event.tags = vec![vec!["t".to_owned(), "-".to_owned()]];
+        assert!(!event.is_protected());
+        event.tags.push(vec!["-".to_owned()]);
+        assert!(event.is_protected());
+    }
+
+    #[test]
+    fn vanish_request_target() {
+        let mut event = Event::simple_event();
+        event.kind = 62;
+        let relay_url = Some("wss://relay.example.com/");
+        assert!(!event.is_vanish_request(relay_url));
+        event.tags = vec![vec![
+            "relay".to_owned(),
+            "wss://other.example.com".to_owned(),
+        ]];
+        assert!(!event.is_vanish_request(relay_url));
+        event.tags = vec![vec![
+            "relay".to_owned(),
+            "wss://relay.example.com".to_owned(),
+        ]];
+        assert!(event.is_vanish_request(relay_url));
+        assert!(!event.is_vanish_request(None));
+        event.tags = vec![vec!["relay".to_owned(), "ALL_RELAYS".to_owned()]];
+        assert!(event.is_vanish_request(None));
+        event.kind = 1;
+        assert!(!event.is_vanish_request(relay_url));
+    }
+
+    #[test]
+    fn past_timestamp_check() {
+        let mut event = Event::simple_event();
+        event.created_at = unix_time() - 120;
+        assert!(!event.is_too_old(None));
+        assert!(!event.is_too_old(Some(3600)));
+        assert!(event.is_too_old(Some(60)));
+    }
+
+    #[test]
+    fn pow_difficulty_count() {
+        let mut event = Event::simple_event();
+        event.id = "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358".to_owned();
+        assert_eq!(event.pow_difficulty(), 21);
+        event.id = "ffff".to_owned();
+        assert_eq!(event.pow_difficulty(), 0);
+        event.id = "0000".to_owned();
+        assert_eq!(event.pow_difficulty(), 16);
+    }
+
+    #[test]
+    fn pow_nonce_commitment() {
+        let mut event = Event::simple_event();
+        assert_eq!(event.pow_commitment(), None);
+        event.tags = vec![vec![
+            "nonce".to_owned(),
+            "776797".to_owned(),
+            "20".to_owned(),
+        ]];
+        assert_eq!(event.pow_commitment(), Some(20));
+    }
+
     #[test]
     fn event_tags_serialize() -> Result<()> {
         // serialize an event with tags to JSON string
@@ -11,11 +11,59 @@ pub const UNIT: &str = "msats";
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_message_length: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_subscriptions: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_filters: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_filter_items: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_limit: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_subid_length: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_event_tags: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_content_length: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_pow_difficulty: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_required: Option<bool>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     payment_required: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     restricted_writes: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at_lower_limit: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at_upper_limit: Option<u64>,
+}
+
+/// Event retention policy, as advertised in NIP-11
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct RetentionPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kinds: Option<Vec<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -61,15 +109,43 @@ pub struct RelayInfo {
     pub payment_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fees: Option<Fees>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Vec<RetentionPolicy>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay_countries: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub posting_policy: Option<String>,
+}
+
+impl RelayInfo {
+    /// Advertise that only some clients may publish events.
+    pub fn restrict_writes(&mut self) {
+        if let Some(l) = self.limitation.as_mut() {
+            l.restricted_writes = Some(true);
+        }
+    }
 }
 
 /// Convert an Info configuration into public Relay Info
 impl From<Settings> for RelayInfo {
     fn from(c: Settings) -> Self {
-        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40];
+        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 26, 33, 40, 45, 50, 62, 77];
 
         if c.authorization.nip42_auth {
             supported_nips.push(42);
+            supported_nips.push(70);
+            supported_nips.sort();
+        }
+
+        if c.authorization.admin_pubkeys.is_some() {
+            supported_nips.push(86);
+            supported_nips.sort();
+        }
+
+        if c.limits.min_pow_difficulty.is_some() || c.limits.min_pow_difficulty_per_kind.is_some() {
+            supported_nips.push(13);
             supported_nips.sort();
         }
 
@@ -77,6 +153,19 @@ impl From<Settings> for RelayInfo {
         let p = c.pay_to_relay;
 
         let limitations = Limitation {
+            max_message_length: c.limits.max_ws_message_bytes,
+            max_subscriptions: Some(c.limits.max_subscriptions),
+            max_filters: c.limits.max_filters,
+            max_filter_items: c.limits.max_filter_items,
+            max_limit: c.limits.max_limit,
+            max_subid_length: Some(c.limits.max_subid_length),
+            max_event_tags: c.limits.max_event_tags,
+            max_content_length: c.limits.max_content_length,
+            min_pow_difficulty: c.limits.min_pow_difficulty,
+            auth_required: Some(
+                c.authorization.auth_required_for_reads
+                    && c.authorization.auth_required_kinds.is_none(),
+            ),
             payment_required: Some(p.enabled),
             restricted_writes: Some(
                 p.enabled
@@ -84,6 +173,23 @@ impl From<Settings> for RelayInfo {
                     || c.authorization.pubkey_whitelist.is_some()
                     || c.grpc.restricts_write,
             ),
+            created_at_lower_limit: c.options.reject_past_seconds.map(|s| s as u64),
+            created_at_upper_limit: c.options.reject_future_seconds.map(|s| s as u64),
+        };
+
+        // advertise the retention limits that apply to all events
+        let retention = if c.retention.is_enabled() {
+            Some(vec![RetentionPolicy {
+                kinds: None,
+                time: c
+                    .retention
+                    .persist_days
+                    .filter(|d| *d > 0)
+                    .map(|d| d as u64 * 86400),
+                count: c.retention.max_events.filter(|n| *n > 0).map(|n| n as u64),
+            }])
+        } else {
+            None
         };
 
         let (payment_url, fees) = if p.enabled {
@@ -136,6 +242,10 @@ impl From<Settings> for RelayInfo {
             payment_url,
             fees,
             icon: i.relay_icon,
+            retention,
+            relay_countries: i.relay_countries,
+            language_tags: i.language_tags,
+            posting_policy: i.posting_policy,
         }
     }
 }
@@ -1,17 +1,22 @@
 pub mod cli;
 pub mod close;
 pub mod config;
+pub mod control;
 pub mod conn;
 pub mod db;
 pub mod delegation;
 pub mod error;
 pub mod event;
 pub mod info;
+pub mod listener;
+pub mod management;
 pub mod nauthz;
+pub mod negentropy;
 pub mod nip05;
 pub mod notice;
 pub mod repo;
 pub mod subscription;
+pub mod tls;
 pub mod utils;
 // Public API for creating relays programmatically
 pub mod payment;
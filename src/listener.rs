@@ -0,0 +1,103 @@
+//! Listeners for relay and admin connections
+use crate::error::Result;
+use hyper::server::accept::Accept;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Which endpoints a listener serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerRole {
+    /// Everything; used when there is no separate admin listener.
+    All,
+    /// The relay, without `/metrics` or the management API.
+    Relay,
+    /// Only `/metrics` and the management API.
+    Admin,
+}
+
+impl ListenerRole {
+    /// Role of a listener, given whether any listener is for admins.
+    #[must_use]
+    pub fn new(admin: bool, has_admin_listener: bool) -> Self {
+        match (admin, has_admin_listener) {
+            (true, _) => ListenerRole::Admin,
+            (false, true) => ListenerRole::Relay,
+            (false, false) => ListenerRole::All,
+        }
+    }
+
+    /// Should a request be served by this listener?
+    #[must_use]
+    pub fn serves(self, admin_request: bool) -> bool {
+        match self {
+            ListenerRole::All => true,
+            ListenerRole::Relay => !admin_request,
+            ListenerRole::Admin => admin_request,
+        }
+    }
+}
+
+/// Connections accepted on a unix domain socket, for serving with
+/// hyper.  The socket file is removed when this is dropped.
+pub struct UnixIncoming {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixIncoming {
+    /// Listen on a unix socket, replacing a stale socket file from a
+    /// previous run, and optionally setting its permissions.
+    pub fn bind(path: &Path, mode: Option<u32>) -> Result<UnixIncoming> {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(UnixIncoming {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for UnixIncoming {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.listener
+            .poll_accept(cx)
+            .map(|r| Some(r.map(|(stream, _)| stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_listener_takes_admin_requests() {
+        let relay = ListenerRole::new(false, true);
+        let admin = ListenerRole::new(true, true);
+        assert!(relay.serves(false) && !relay.serves(true));
+        assert!(admin.serves(true) && !admin.serves(false));
+        let all = ListenerRole::new(false, false);
+        assert!(all.serves(true) && all.serves(false));
+    }
+}
@@ -3,16 +3,16 @@ use clap::Parser;
 use console_subscriber::ConsoleLayer;
 use nostr_rs_relay::cli::CLIArgs;
 use nostr_rs_relay::config;
-use nostr_rs_relay::server::start_server;
+use nostr_rs_relay::control::{ControlMessage, RelayHandle};
 use std::fs;
 use std::path::Path;
 use std::process;
-use std::sync::mpsc as syncmpsc;
-use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
+use std::sync::mpsc::Sender as MpscSender;
 use std::thread;
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
-use tracing::info;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::EnvFilter;
 
@@ -51,11 +51,37 @@ fn main() {
     let mut _log_guard: Option<WorkerGuard> = None;
 
     // configure settings from the config file (defaults to config.toml)
-    // replace default settings with those read from the config file
-    let mut settings = config::Settings::new(&config_file_arg).unwrap_or_else(|e| {
-        eprintln!("Error reading config file ({:?})", e);
-        process::exit(1);
-    });
+    // replace default settings with those read from the config file,
+    // then with any environment variables and --set overrides
+    let overrides = args.set;
+    // a broken default config file is ignored, unless it is being checked
+    let strict = args.check_config;
+    let mut settings = config::Settings::with_overrides(&config_file_arg, &overrides, strict)
+        .unwrap_or_else(|e| {
+            eprintln!("Error reading config file: {}", e);
+            process::exit(1);
+        });
+
+    // get database directory from args
+    let db_dir_arg = args.db;
+
+    // update with database location from args, if provided
+    if let Some(db_dir) = &db_dir_arg {
+        settings.database.data_directory = db_dir.clone();
+    }
+
+    // print the resolved settings and stop, if only checking the config
+    if args.check_config {
+        match serde_json::to_string_pretty(&settings.redacted()) {
+            Ok(s) => println!("{}", s),
+            Err(e) => {
+                eprintln!("Could not print settings: {}", e);
+                process::exit(1);
+            }
+        }
+        eprintln!("Config is valid");
+        return;
+    }
 
     // setup tracing
     if settings.diagnostics.tracing {
@@ -87,23 +113,49 @@ fn main() {
     }
     info!("Starting up from main");
 
-    // get database directory from args
-    let db_dir_arg = args.db;
+    // run the relay in a new thread.  Applications embedding the
+    // relay can drive it through the handle's control channel.
+    let relay = RelayHandle::start(&settings);
+    // reload the config file when asked to
+    let ctrl_tx = relay.sender();
+    thread::spawn(move || reload_on_hangup(&config_file_arg, &overrides, &db_dir_arg, &ctrl_tx));
+    // block on nostr thread to finish.
+    let _svr = relay.join();
+}
 
-    // update with database location from args, if provided
-    if let Some(db_dir) = db_dir_arg {
-        settings.database.data_directory = db_dir;
-    }
-    // we should have a 'control plane' channel to monitor and bump
-    // the server.  this will let us do stuff like clear the database,
-    // shutdown, etc.; for now all this does is initiate shutdown if
-    // `()` is sent.  This will change in the future, this is just a
-    // stopgap to shutdown the relay when it is used as a library.
-    let (_, ctrl_rx): (MpscSender<()>, MpscReceiver<()>) = syncmpsc::channel();
-    // run this in a new thread
-    let handle = thread::spawn(move || {
-        let _svr = start_server(&settings, ctrl_rx);
+/// Re-read the config file and environment whenever a SIGHUP is
+/// received, and send the new settings to the relay.
+fn reload_on_hangup(
+    config_file_arg: &Option<String>,
+    overrides: &[(String, String)],
+    db_dir_arg: &Option<String>,
+    ctrl_tx: &MpscSender<ControlMessage>,
+) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let mut hangup = signal(SignalKind::hangup()).expect("could not define signal");
+        while hangup.recv().await.is_some() {
+            info!("reloading config due to SIGHUP");
+            // keep the current settings if the config file is broken
+            let reloaded = config::Settings::with_overrides(config_file_arg, overrides, true);
+            let mut settings = match reloaded {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("config was not reloaded: {}", e);
+                    continue;
+                }
+            };
+            if let Some(db_dir) = db_dir_arg {
+                settings.database.data_directory = db_dir.clone();
+            }
+            let msg = ControlMessage::ReloadSettings(Box::new(settings));
+            if ctrl_tx.send(msg).is_err() {
+                // the relay has stopped
+                break;
+            }
+        }
     });
-    // block on nostr thread to finish.
-    handle.join().unwrap();
 }
@@ -0,0 +1,787 @@
+//! Relay management API (NIP-86)
+//!
+//! Admins send JSON-RPC requests over HTTP, authenticated with a
+//! NIP-98 event signed by one of the configured admin pubkeys.
+//! Changes are stored in the repository and applied to the shared
+//! [`RelayPolicy`], so they take effect without a restart.
+use crate::config::Settings;
+use crate::error::Result;
+use crate::event::Event;
+use crate::info::RelayInfo;
+use crate::repo::NostrRepo;
+use crate::utils::{host_str, is_lower_hex, unix_time};
+use base64::{engine::general_purpose, Engine as _};
+use bitcoin_hashes::{sha256, Hash};
+use hyper::body::to_bytes;
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, warn};
+
+/// Content type of management requests.
+pub const CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+/// Event kind for NIP-98 HTTP authorization.
+const HTTP_AUTH_KIND: u64 = 27235;
+
+/// Maximum difference, in seconds, between an authorization event
+/// timestamp and the current time.
+const HTTP_AUTH_WINDOW: u64 = 60;
+
+/// Methods supported by the management API.
+const SUPPORTED_METHODS: &[&str] = &[
+    "supportedmethods",
+    "banpubkey",
+    "allowpubkey",
+    "listbannedpubkeys",
+    "listallowedpubkeys",
+    "banevent",
+    "listbannedevents",
+    "allowkind",
+    "disallowkind",
+    "listallowedkinds",
+    "listdisallowedkinds",
+    "blockip",
+    "unblockip",
+    "listblockedips",
+    "changerelayname",
+];
+
+/// Lists maintained through the management API.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PolicyList {
+    /// Pubkeys allowed to publish.  If empty, anyone may publish.
+    AllowedPubkey,
+    /// Pubkeys that may not publish.
+    BannedPubkey,
+    /// Kinds that may be published.  If empty, any kind may be published.
+    AllowedKind,
+    /// Kinds that may not be published.
+    DisallowedKind,
+    /// Event ids that may not be published.
+    BannedEvent,
+    /// IP addresses that may not connect.
+    BlockedIp,
+}
+
+impl PolicyList {
+    /// Name of the list, as stored in the database.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            PolicyList::AllowedPubkey => "allowed_pubkey",
+            PolicyList::BannedPubkey => "banned_pubkey",
+            PolicyList::AllowedKind => "allowed_kind",
+            PolicyList::DisallowedKind => "disallowed_kind",
+            PolicyList::BannedEvent => "banned_event",
+            PolicyList::BlockedIp => "blocked_ip",
+        }
+    }
+
+    /// Find a list by its stored name.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<PolicyList> {
+        match name {
+            "allowed_pubkey" => Some(PolicyList::AllowedPubkey),
+            "banned_pubkey" => Some(PolicyList::BannedPubkey),
+            "allowed_kind" => Some(PolicyList::AllowedKind),
+            "disallowed_kind" => Some(PolicyList::DisallowedKind),
+            "banned_event" => Some(PolicyList::BannedEvent),
+            "blocked_ip" => Some(PolicyList::BlockedIp),
+            _ => None,
+        }
+    }
+}
+
+/// An entry in a management list, as stored in the database.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PolicyEntry {
+    pub list: PolicyList,
+    /// Pubkey, event id, kind, or IP address
+    pub item: String,
+    pub reason: Option<String>,
+}
+
+/// Relay settings that can be changed through the management API.
+const SETTING_NAME: &str = "name";
+
+/// Current management lists and settings, consulted when accepting
+/// connections and events.
+#[derive(Clone, Debug, Default)]
+pub struct RelayPolicy {
+    lists: HashMap<PolicyList, BTreeMap<String, Option<String>>>,
+    relay_name: Option<String>,
+}
+
+/// Relay policy shared between the web server and database writer.
+pub type SharedPolicy = Arc<RwLock<RelayPolicy>>;
+
+impl RelayPolicy {
+    /// Create a policy from the allow and block lists in the config.
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> RelayPolicy {
+        let mut policy = RelayPolicy::default();
+        for pk in settings.authorization.pubkey_whitelist.iter().flatten() {
+            policy.insert(PolicyList::AllowedPubkey, pk, None);
+        }
+        for k in settings.limits.event_kind_allowlist.iter().flatten() {
+            policy.insert(PolicyList::AllowedKind, &k.to_string(), None);
+        }
+        for k in settings.limits.event_kind_blacklist.iter().flatten() {
+            policy.insert(PolicyList::DisallowedKind, &k.to_string(), None);
+        }
+        policy
+    }
+
+    /// Add an item to a list, replacing any existing reason.
+    pub fn insert(&mut self, list: PolicyList, item: &str, reason: Option<String>) {
+        self.lists
+            .entry(list)
+            .or_default()
+            .insert(item.to_owned(), reason);
+    }
+
+    /// Remove an item from a list.
+    pub fn remove(&mut self, list: PolicyList, item: &str) {
+        if let Some(l) = self.lists.get_mut(&list) {
+            l.remove(item);
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, list: PolicyList, item: &str) -> bool {
+        self.lists.get(&list).is_some_and(|l| l.contains_key(item))
+    }
+
+    /// Items in a list, with the reason they were added.
+    #[must_use]
+    pub fn entries(&self, list: PolicyList) -> Vec<(String, Option<String>)> {
+        self.lists
+            .get(&list)
+            .map(|l| l.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    fn is_empty(&self, list: PolicyList) -> bool {
+        self.lists.get(&list).is_none_or(BTreeMap::is_empty)
+    }
+
+    /// Is the event author (or delegator) banned?
+    #[must_use]
+    pub fn is_pubkey_banned(&self, event: &Event) -> bool {
+        self.contains(PolicyList::BannedPubkey, &event.pubkey)
+            || event
+                .delegated_by
+                .as_ref()
+                .is_some_and(|d| self.contains(PolicyList::BannedPubkey, d))
+    }
+
+    /// Is the event author (or delegator) on the allowlist?  `None`
+    /// if there is no allowlist.
+    #[must_use]
+    pub fn is_pubkey_allowed(&self, event: &Event) -> Option<bool> {
+        if self.is_empty(PolicyList::AllowedPubkey) {
+            return None;
+        }
+        Some(
+            self.contains(PolicyList::AllowedPubkey, &event.pubkey)
+                || event
+                    .delegated_by
+                    .as_ref()
+                    .is_some_and(|d| self.contains(PolicyList::AllowedPubkey, d)),
+        )
+    }
+
+    /// May events of this kind be published?
+    #[must_use]
+    pub fn is_kind_allowed(&self, kind: u64) -> bool {
+        let kind = kind.to_string();
+        !self.contains(PolicyList::DisallowedKind, &kind)
+            && (self.is_empty(PolicyList::AllowedKind)
+                || self.contains(PolicyList::AllowedKind, &kind))
+    }
+
+    #[must_use]
+    pub fn is_event_banned(&self, id: &str) -> bool {
+        self.contains(PolicyList::BannedEvent, id)
+    }
+
+    #[must_use]
+    pub fn is_ip_blocked(&self, ip: &str) -> bool {
+        self.contains(PolicyList::BlockedIp, ip)
+    }
+
+    /// Apply management changes to the relay information document.
+    pub fn update_relay_info(&self, info: &mut RelayInfo) {
+        if let Some(name) = &self.relay_name {
+            info.name = Some(name.clone());
+        }
+        if !self.is_empty(PolicyList::AllowedPubkey) {
+            info.restrict_writes();
+        }
+    }
+}
+
+/// Load the relay policy: the config lists, plus any changes made
+/// through the management API.
+pub async fn load_policy(repo: &dyn NostrRepo, settings: &Settings) -> Result<RelayPolicy> {
+    let mut policy = RelayPolicy::from_settings(settings);
+    for e in repo.get_policy_entries().await? {
+        policy.insert(e.list, &e.item, e.reason);
+    }
+    for (name, value) in repo.get_relay_settings().await? {
+        if name == SETTING_NAME {
+            policy.relay_name = Some(value);
+        }
+    }
+    Ok(policy)
+}
+
+/// Is this request for the management API?
+#[must_use]
+pub fn is_management_request(request: &Request<Body>) -> bool {
+    request.method() == hyper::Method::POST
+        && request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|ct| ct.starts_with(CONTENT_TYPE))
+}
+
+/// A JSON-RPC management request.
+#[derive(Deserialize, Debug)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+/// Build a JSON-RPC response.
+fn rpc_response(status: StatusCode, result: std::result::Result<Value, String>) -> Response<Body> {
+    let body = match result {
+        Ok(result) => json!({ "result": result }),
+        Err(error) => json!({ "result": null, "error": error }),
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Handle a management API request.
+pub async fn handle_request(
+    request: Request<Body>,
+    repo: Arc<dyn NostrRepo>,
+    policy: SharedPolicy,
+    settings: &Settings,
+) -> Response<Body> {
+    let auth_header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .map(ToOwned::to_owned);
+    let Ok(body) = to_bytes(request.into_body()).await else {
+        return rpc_response(
+            StatusCode::BAD_REQUEST,
+            Err("could not read request".into()),
+        );
+    };
+    let admin = match authorize(auth_header.as_deref(), &body, settings) {
+        Ok(pubkey) => pubkey,
+        Err(e) => {
+            info!("management request refused: {}", e);
+            return rpc_response(StatusCode::UNAUTHORIZED, Err(e.to_owned()));
+        }
+    };
+    let req: RpcRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(_) => {
+            return rpc_response(StatusCode::BAD_REQUEST, Err("invalid request".into()));
+        }
+    };
+    info!(
+        "management request: {} {:?} (admin: {:?})",
+        req.method, req.params, admin
+    );
+    rpc_response(StatusCode::OK, dispatch(&req, repo.as_ref(), &policy).await)
+}
+
+/// Check the NIP-98 authorization header of a management request,
+/// returning the admin pubkey that signed it.
+fn authorize(
+    auth_header: Option<&str>,
+    body: &[u8],
+    settings: &Settings,
+) -> std::result::Result<String, &'static str> {
+    let admins = settings
+        .authorization
+        .admin_pubkeys
+        .as_ref()
+        .ok_or("management API is not enabled")?;
+    let encoded = auth_header
+        .and_then(|h| h.strip_prefix("Nostr "))
+        .ok_or("missing authorization")?;
+    let event: Event = general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or("malformed authorization")?;
+    if event.kind != HTTP_AUTH_KIND || event.validate().is_err() {
+        return Err("invalid authorization event");
+    }
+    if event.created_at.abs_diff(unix_time()) > HTTP_AUTH_WINDOW {
+        return Err("authorization event is too old");
+    }
+    let tag = |name| event.tag_values_by_name(name).into_iter().next();
+    if !tag("method").is_some_and(|m| m.eq_ignore_ascii_case("POST")) {
+        return Err("authorization is for another method");
+    }
+    // the relay may be behind a proxy, so only the host is compared.
+    if let Some(relay_host) = settings.info.relay_url.as_deref().and_then(host_str) {
+        if tag("u").and_then(|u| host_str(&u)) != Some(relay_host) {
+            return Err("authorization is for another URL");
+        }
+    }
+    let payload = format!("{:x}", sha256::Hash::hash(body));
+    if tag("payload") != Some(payload) {
+        return Err("authorization payload does not match request");
+    }
+    if !admins.contains(&event.pubkey) {
+        return Err("pubkey is not a relay admin");
+    }
+    Ok(event.pubkey)
+}
+
+/// Get a required string parameter.
+fn str_param(params: &[Value], i: usize, what: &str) -> std::result::Result<String, String> {
+    params
+        .get(i)
+        .and_then(Value::as_str)
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| format!("missing {what}"))
+}
+
+/// Get a 32-byte hex parameter, such as a pubkey or event id.
+fn hex_param(params: &[Value], what: &str) -> std::result::Result<String, String> {
+    let v = str_param(params, 0, what)?;
+    if v.len() == 64 && is_lower_hex(&v) {
+        Ok(v)
+    } else {
+        Err(format!("invalid {what}"))
+    }
+}
+
+/// Get the optional reason parameter that follows the item.
+fn reason_param(params: &[Value]) -> Option<String> {
+    params
+        .get(1)
+        .and_then(Value::as_str)
+        .filter(|r| !r.is_empty())
+        .map(ToOwned::to_owned)
+}
+
+/// Get a kind parameter.
+fn kind_param(params: &[Value]) -> std::result::Result<String, String> {
+    params
+        .first()
+        .and_then(Value::as_u64)
+        .map(|k| k.to_string())
+        .ok_or_else(|| "missing kind".to_owned())
+}
+
+/// Get an IP address parameter.
+fn ip_param(params: &[Value]) -> std::result::Result<String, String> {
+    str_param(params, 0, "ip")?
+        .parse::<IpAddr>()
+        .map(|ip| ip.to_string())
+        .map_err(|_| "invalid ip".to_owned())
+}
+
+/// Log a database failure, and describe it to the admin.
+fn db_error(e: &crate::error::Error) -> String {
+    warn!("management request failed: {:?}", e);
+    "database error".to_owned()
+}
+
+/// Store an item in a list, removing it from a conflicting list.
+async fn store_entry(
+    repo: &dyn NostrRepo,
+    policy: &SharedPolicy,
+    list: PolicyList,
+    item: &str,
+    reason: Option<String>,
+    replaces: Option<PolicyList>,
+) -> Result<()> {
+    if let Some(other) = replaces {
+        repo.remove_policy_entry(other, item).await?;
+    }
+    repo.add_policy_entry(list, item, reason.as_deref()).await?;
+    let mut p = policy.write().unwrap();
+    if let Some(other) = replaces {
+        p.remove(other, item);
+    }
+    p.insert(list, item, reason);
+    Ok(())
+}
+
+/// Add an item to a list, removing it from a conflicting list.
+async fn add_entry(
+    repo: &dyn NostrRepo,
+    policy: &SharedPolicy,
+    list: PolicyList,
+    item: &str,
+    reason: Option<String>,
+    replaces: Option<PolicyList>,
+) -> std::result::Result<(), String> {
+    store_entry(repo, policy, list, item, reason, replaces)
+        .await
+        .map_err(|e| db_error(&e))
+}
+
+/// Ban a pubkey, and hide the events it has already published.
+/// Returns the number of events hidden.
+pub async fn ban_pubkey(
+    repo: &dyn NostrRepo,
+    policy: &SharedPolicy,
+    pubkey: &str,
+    reason: Option<String>,
+) -> Result<u64> {
+    store_entry(
+        repo,
+        policy,
+        PolicyList::BannedPubkey,
+        pubkey,
+        reason,
+        Some(PolicyList::AllowedPubkey),
+    )
+    .await?;
+    let hidden = repo.hide_author_events(pubkey).await?;
+    debug!("hid {} events from banned pubkey {:?}", hidden, pubkey);
+    Ok(hidden)
+}
+
+/// Remove an item from a list.
+async fn remove_entry(
+    repo: &dyn NostrRepo,
+    policy: &SharedPolicy,
+    list: PolicyList,
+    item: &str,
+) -> std::result::Result<(), String> {
+    repo.remove_policy_entry(list, item)
+        .await
+        .map_err(|e| db_error(&e))?;
+    policy.write().unwrap().remove(list, item);
+    Ok(())
+}
+
+/// List the entries of a list, as objects with a reason.
+fn list_entries(policy: &SharedPolicy, list: PolicyList, key: &str) -> Value {
+    let entries = policy.read().unwrap().entries(list);
+    Value::Array(
+        entries
+            .into_iter()
+            .map(|(item, reason)| json!({ key: item, "reason": reason }))
+            .collect(),
+    )
+}
+
+/// List the kinds in a list.
+fn list_kinds(policy: &SharedPolicy, list: PolicyList) -> Value {
+    let entries = policy.read().unwrap().entries(list);
+    let mut kinds: Vec<u64> = entries.iter().filter_map(|(k, _)| k.parse().ok()).collect();
+    kinds.sort_unstable();
+    json!(kinds)
+}
+
+/// Run a management method.
+async fn dispatch(
+    req: &RpcRequest,
+    repo: &dyn NostrRepo,
+    policy: &SharedPolicy,
+) -> std::result::Result<Value, String> {
+    let params = &req.params;
+    match req.method.as_str() {
+        "supportedmethods" => Ok(json!(SUPPORTED_METHODS)),
+        "banpubkey" => {
+            let pubkey = hex_param(params, "pubkey")?;
+            ban_pubkey(repo, policy, &pubkey, reason_param(params))
+                .await
+                .map_err(|e| db_error(&e))?;
+            Ok(json!(true))
+        }
+        "allowpubkey" => {
+            let pubkey = hex_param(params, "pubkey")?;
+            // allowing a banned pubkey lifts the ban; otherwise the
+            // pubkey is added to the allowlist.
+            let banned = policy
+                .read()
+                .unwrap()
+                .contains(PolicyList::BannedPubkey, &pubkey);
+            if banned {
+                remove_entry(repo, policy, PolicyList::BannedPubkey, &pubkey).await?;
+            } else {
+                add_entry(
+                    repo,
+                    policy,
+                    PolicyList::AllowedPubkey,
+                    &pubkey,
+                    reason_param(params),
+                    None,
+                )
+                .await?;
+            }
+            Ok(json!(true))
+        }
+        "listbannedpubkeys" => Ok(list_entries(policy, PolicyList::BannedPubkey, "pubkey")),
+        "listallowedpubkeys" => Ok(list_entries(policy, PolicyList::AllowedPubkey, "pubkey")),
+        "banevent" => {
+            let id = hex_param(params, "event id")?;
+            add_entry(
+                repo,
+                policy,
+                PolicyList::BannedEvent,
+                &id,
+                reason_param(params),
+                None,
+            )
+            .await?;
+            repo.hide_event(&id).await.map_err(|e| db_error(&e))?;
+            Ok(json!(true))
+        }
+        "listbannedevents" => Ok(list_entries(policy, PolicyList::BannedEvent, "id")),
+        "allowkind" => {
+            let kind = kind_param(params)?;
+            // allowing a disallowed kind lifts the block; otherwise
+            // the kind is added to the allowlist.
+            let blocked = policy
+                .read()
+                .unwrap()
+                .contains(PolicyList::DisallowedKind, &kind);
+            if blocked {
+                remove_entry(repo, policy, PolicyList::DisallowedKind, &kind).await?;
+            } else {
+                add_entry(repo, policy, PolicyList::AllowedKind, &kind, None, None).await?;
+            }
+            Ok(json!(true))
+        }
+        "disallowkind" => {
+            let kind = kind_param(params)?;
+            add_entry(
+                repo,
+                policy,
+                PolicyList::DisallowedKind,
+                &kind,
+                None,
+                Some(PolicyList::AllowedKind),
+            )
+            .await?;
+            Ok(json!(true))
+        }
+        "listallowedkinds" => Ok(list_kinds(policy, PolicyList::AllowedKind)),
+        "listdisallowedkinds" => Ok(list_kinds(policy, PolicyList::DisallowedKind)),
+        "blockip" => {
+            let ip = ip_param(params)?;
+            add_entry(
+                repo,
+                policy,
+                PolicyList::BlockedIp,
+                &ip,
+                reason_param(params),
+                None,
+            )
+            .await?;
+            Ok(json!(true))
+        }
+        "unblockip" => {
+            let ip = ip_param(params)?;
+            remove_entry(repo, policy, PolicyList::BlockedIp, &ip).await?;
+            Ok(json!(true))
+        }
+        "listblockedips" => Ok(list_entries(policy, PolicyList::BlockedIp, "ip")),
+        "changerelayname" => {
+            let name = str_param(params, 0, "name")?;
+            repo.set_relay_setting(SETTING_NAME, &name)
+                .await
+                .map_err(|e| db_error(&e))?;
+            policy.write().unwrap().relay_name = Some(name);
+            Ok(json!(true))
+        }
+        _ => Err(format!("unsupported method: {}", req.method)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{KeyPair, Message, Secp256k1, XOnlyPublicKey};
+
+    fn event_by(pubkey: &str, delegated_by: Option<&str>) -> Event {
+        let mut e = Event::simple_event();
+        e.pubkey = pubkey.to_owned();
+        e.delegated_by = delegated_by.map(ToOwned::to_owned);
+        e
+    }
+
+    #[test]
+    fn pubkey_lists() {
+        let mut policy = RelayPolicy::default();
+        assert_eq!(policy.is_pubkey_allowed(&event_by("a", None)), None);
+        policy.insert(PolicyList::AllowedPubkey, "a", None);
+        policy.insert(PolicyList::BannedPubkey, "c", Some("spam".into()));
+        assert_eq!(policy.is_pubkey_allowed(&event_by("a", None)), Some(true));
+        assert_eq!(
+            policy.is_pubkey_allowed(&event_by("b", Some("a"))),
+            Some(true)
+        );
+        assert_eq!(policy.is_pubkey_allowed(&event_by("b", None)), Some(false));
+        assert!(policy.is_pubkey_banned(&event_by("b", Some("c"))));
+        assert!(!policy.is_pubkey_banned(&event_by("b", None)));
+        assert_eq!(
+            policy.entries(PolicyList::BannedPubkey),
+            vec![("c".to_owned(), Some("spam".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn kind_lists() {
+        let mut policy = RelayPolicy::default();
+        assert!(policy.is_kind_allowed(1));
+        policy.insert(PolicyList::DisallowedKind, "4", None);
+        assert!(!policy.is_kind_allowed(4));
+        assert!(policy.is_kind_allowed(1));
+        policy.insert(PolicyList::AllowedKind, "1", None);
+        assert!(policy.is_kind_allowed(1));
+        assert!(!policy.is_kind_allowed(7));
+        policy.remove(PolicyList::AllowedKind, "1");
+        assert!(policy.is_kind_allowed(7));
+    }
+
+    #[test]
+    fn policy_from_config() {
+        let mut settings = Settings::default();
+        settings.authorization.pubkey_whitelist = Some(vec!["a".to_owned()]);
+        settings.limits.event_kind_blacklist = Some(vec![4]);
+        let policy = RelayPolicy::from_settings(&settings);
+        assert_eq!(policy.is_pubkey_allowed(&event_by("b", None)), Some(false));
+        assert!(!policy.is_kind_allowed(4));
+        assert!(policy.is_kind_allowed(1));
+    }
+
+    #[test]
+    fn list_names_roundtrip() {
+        for list in [
+            PolicyList::AllowedPubkey,
+            PolicyList::BannedPubkey,
+            PolicyList::AllowedKind,
+            PolicyList::DisallowedKind,
+            PolicyList::BannedEvent,
+            PolicyList::BlockedIp,
+        ] {
+            assert_eq!(PolicyList::from_name(list.name()), Some(list));
+        }
+    }
+
+    #[test]
+    fn unauthorized_without_admins() {
+        let settings = Settings::default();
+        assert_eq!(
+            authorize(Some("Nostr e30="), b"{}", &settings),
+            Err("management API is not enabled")
+        );
+    }
+
+    /// Settings with one admin, and a NIP-98 header for a request
+    /// body, signed by `signer`.
+    fn signed_request(admin: &KeyPair, signer: &KeyPair, body: &[u8]) -> (Settings, String) {
+        let mut settings = Settings::default();
+        let admin_pk = XOnlyPublicKey::from_keypair(admin).to_string();
+        settings.authorization.admin_pubkeys = Some(vec![admin_pk]);
+        let mut event = Event::simple_event();
+        event.kind = HTTP_AUTH_KIND;
+        event.pubkey = XOnlyPublicKey::from_keypair(signer).to_string();
+        event.created_at = unix_time();
+        event.tags = vec![
+            vec!["u".to_owned(), "https://relay.example.com/".to_owned()],
+            vec!["method".to_owned(), "POST".to_owned()],
+            vec![
+                "payload".to_owned(),
+                format!("{:x}", sha256::Hash::hash(body)),
+            ],
+        ];
+        let digest = sha256::Hash::hash(event.to_canonical().unwrap().as_bytes());
+        let msg = Message::from_slice(digest.as_ref()).unwrap();
+        event.id = format!("{digest:x}");
+        event.sig = Secp256k1::new().sign_schnorr(&msg, signer).to_string();
+        let encoded = general_purpose::STANDARD.encode(serde_json::to_string(&event).unwrap());
+        (settings, format!("Nostr {encoded}"))
+    }
+
+    fn new_keys() -> KeyPair {
+        KeyPair::new(&Secp256k1::new(), &mut secp256k1::rand::thread_rng())
+    }
+
+    #[test]
+    fn authorized_by_admin() {
+        let admin = new_keys();
+        let body = br#"{"method":"supportedmethods","params":[]}"#;
+        let (settings, header) = signed_request(&admin, &admin, body);
+        assert_eq!(
+            authorize(Some(&header), body, &settings),
+            Ok(XOnlyPublicKey::from_keypair(&admin).to_string())
+        );
+    }
+
+    #[test]
+    fn unauthorized_for_other_body() {
+        let admin = new_keys();
+        let (settings, header) = signed_request(&admin, &admin, b"{}");
+        assert_eq!(
+            authorize(Some(&header), br#"{"method":"banpubkey"}"#, &settings),
+            Err("authorization payload does not match request")
+        );
+    }
+
+    #[test]
+    fn unauthorized_for_other_pubkey() {
+        let (settings, header) = signed_request(&new_keys(), &new_keys(), b"{}");
+        assert_eq!(
+            authorize(Some(&header), b"{}", &settings),
+            Err("pubkey is not a relay admin")
+        );
+    }
+
+    #[tokio::test]
+    async fn ban_and_allow_pubkeys() {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = crate::db::build_repo(&settings, metrics).await;
+        let policy: SharedPolicy = Arc::default();
+        let call = |method: &str, pubkey: &str| RpcRequest {
+            method: method.to_owned(),
+            params: vec![json!(pubkey)],
+        };
+        let (spammer, friend) = ("a".repeat(64), "b".repeat(64));
+        let by_spammer = event_by(&spammer, None);
+        let banned = dispatch(&call("banpubkey", &spammer), repo.as_ref(), &policy).await;
+        assert_eq!(banned, Ok(json!(true)));
+        assert!(policy.read().unwrap().is_pubkey_banned(&by_spammer));
+        // allowing a banned pubkey only lifts the ban
+        let allowed = dispatch(&call("allowpubkey", &spammer), repo.as_ref(), &policy).await;
+        assert_eq!(allowed, Ok(json!(true)));
+        assert!(!policy.read().unwrap().is_pubkey_banned(&by_spammer));
+        assert!(repo.get_policy_entries().await.unwrap().is_empty());
+        // otherwise, the pubkey is added to the allowlist
+        let allowed = dispatch(&call("allowpubkey", &friend), repo.as_ref(), &policy).await;
+        assert_eq!(allowed, Ok(json!(true)));
+        let listed = dispatch(&call("listallowedpubkeys", ""), repo.as_ref(), &policy).await;
+        assert_eq!(listed, Ok(json!([{"pubkey": friend, "reason": null}])));
+        let allowed = policy.read().unwrap().is_pubkey_allowed(&by_spammer);
+        assert_eq!(allowed, Some(false));
+    }
+}
@@ -0,0 +1,693 @@
+//! Negentropy set reconciliation (NIP-77)
+//!
+//! Clients (or other relays) open a session for a filter, and then
+//! exchange messages with the relay to discover which events each side
+//! is missing.  The relay only ever responds to messages, so only the
+//! non-initiator half of the protocol is implemented here.
+use crate::subscription::ReqFilter;
+use bitcoin_hashes::{sha256, Hash};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Protocol version supported by the relay.
+const PROTOCOL_VERSION: u8 = 0x61;
+
+/// Size of an event id.
+const ID_SIZE: usize = 32;
+
+/// Size of a range fingerprint.
+const FINGERPRINT_SIZE: usize = 16;
+
+/// Ranges with fewer items than this are sent as id lists, larger
+/// ranges are split into this many buckets.
+const BUCKETS: usize = 16;
+
+/// Responses are kept below this size (in bytes, before hex
+/// encoding).  Anything that does not fit is summarized in a final
+/// fingerprint, and the client continues with another message.
+pub const FRAME_SIZE_LIMIT: usize = 60_000;
+
+/// Room left under the frame size limit for the end of a response:
+/// the bound, mode and count of a truncated id list, and the final
+/// fingerprint.
+const FRAME_HEADROOM: usize = 200;
+
+/// Reconciliation modes for a range.
+const MODE_SKIP: u64 = 0;
+const MODE_FINGERPRINT: u64 = 1;
+const MODE_ID_LIST: u64 = 2;
+
+/// An event, as seen by the reconciliation protocol.  Items are
+/// ordered by timestamp, then id.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Item {
+    pub timestamp: u64,
+    pub id: [u8; ID_SIZE],
+}
+
+/// The upper bound of a range, which may be a prefix of an id.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Bound {
+    timestamp: u64,
+    id: Vec<u8>,
+}
+
+impl Bound {
+    fn new(timestamp: u64) -> Bound {
+        Bound {
+            timestamp,
+            id: vec![],
+        }
+    }
+
+    /// Is an item before this bound?
+    fn is_after(&self, item: &Item) -> bool {
+        if item.timestamp != self.timestamp {
+            return item.timestamp < self.timestamp;
+        }
+        // bound ids are implicitly zero-padded
+        let mut padded = [0; ID_SIZE];
+        padded[..self.id.len()].copy_from_slice(&self.id);
+        item.id < padded
+    }
+
+    /// The shortest bound that separates two consecutive items.
+    fn minimal(prev: &Item, curr: &Item) -> Bound {
+        if curr.timestamp != prev.timestamp {
+            return Bound::new(curr.timestamp);
+        }
+        let shared = curr
+            .id
+            .iter()
+            .zip(prev.id.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        Bound {
+            timestamp: curr.timestamp,
+            id: curr.id[..=shared.min(ID_SIZE - 1)].to_vec(),
+        }
+    }
+}
+
+/// Encode an integer as a big-endian base-128 varint.
+fn encode_varint(mut n: u64, out: &mut Vec<u8>) {
+    let mut digits = vec![(n & 0x7f) as u8];
+    n >>= 7;
+    while n > 0 {
+        digits.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    out.extend(digits.iter().rev());
+}
+
+/// Reader for an incoming message.
+struct Decoder<'a> {
+    buf: &'a [u8],
+    last_timestamp: u64,
+}
+
+impl<'a> Decoder<'a> {
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < n {
+            return Err("message is truncated".to_owned());
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n: u64 = 0;
+        loop {
+            let b = self.bytes(1)?[0];
+            if n.leading_zeros() < 7 {
+                return Err("varint overflow".to_owned());
+            }
+            n = (n << 7) | u64::from(b & 0x7f);
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    /// Timestamps are delta-encoded from the previous one, with zero
+    /// reserved for infinity.
+    fn timestamp(&mut self) -> Result<u64, String> {
+        let n = self.varint()?;
+        if n == 0 || self.last_timestamp == u64::MAX {
+            self.last_timestamp = u64::MAX;
+        } else {
+            self.last_timestamp = self.last_timestamp.saturating_add(n - 1);
+        }
+        Ok(self.last_timestamp)
+    }
+
+    fn bound(&mut self) -> Result<Bound, String> {
+        let timestamp = self.timestamp()?;
+        let len = self.varint()?;
+        if len > ID_SIZE as u64 {
+            return Err("bound id is too long".to_owned());
+        }
+        let id = self.bytes(len as usize)?.to_vec();
+        Ok(Bound { timestamp, id })
+    }
+}
+
+/// Writer for an outgoing message.
+struct Encoder {
+    last_timestamp: u64,
+}
+
+impl Encoder {
+    fn timestamp(&mut self, timestamp: u64, out: &mut Vec<u8>) {
+        if timestamp == u64::MAX {
+            self.last_timestamp = u64::MAX;
+            encode_varint(0, out);
+        } else {
+            encode_varint(timestamp - self.last_timestamp + 1, out);
+            self.last_timestamp = timestamp;
+        }
+    }
+
+    fn bound(&mut self, bound: &Bound, out: &mut Vec<u8>) {
+        self.timestamp(bound.timestamp, out);
+        encode_varint(bound.id.len() as u64, out);
+        out.extend_from_slice(&bound.id);
+    }
+}
+
+/// The set of events matched by a session's filter, sorted for
+/// reconciliation.
+#[derive(Clone, Debug, Default)]
+pub struct Storage {
+    items: Vec<Item>,
+}
+
+impl Storage {
+    /// Create storage from (possibly unsorted and duplicated) items.
+    #[must_use]
+    pub fn new(mut items: Vec<Item>) -> Storage {
+        items.sort_unstable();
+        items.dedup();
+        Storage { items }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Index of the first item at or after `bound`, searching from
+    /// `start`.
+    fn lower_bound(&self, start: usize, bound: &Bound) -> usize {
+        start + self.items[start..].partition_point(|i| bound.is_after(i))
+    }
+
+    /// Fingerprint of a range of items: the sum of their ids (as
+    /// little-endian 256-bit integers), hashed with the item count.
+    fn fingerprint(&self, lower: usize, upper: usize) -> [u8; FINGERPRINT_SIZE] {
+        let mut sum = [0u8; ID_SIZE];
+        for item in &self.items[lower..upper] {
+            let mut carry = 0u16;
+            for (s, b) in sum.iter_mut().zip(item.id.iter()) {
+                let v = u16::from(*s) + u16::from(*b) + carry;
+                *s = v as u8;
+                carry = v >> 8;
+            }
+        }
+        let mut input = sum.to_vec();
+        encode_varint((upper - lower) as u64, &mut input);
+        let hash = sha256::Hash::hash(&input);
+        let mut fp = [0u8; FINGERPRINT_SIZE];
+        fp.copy_from_slice(&hash[..FINGERPRINT_SIZE]);
+        fp
+    }
+
+    /// Describe a range that differs from the client's, either by
+    /// listing its ids, or by fingerprinting smaller buckets.
+    fn split_range(
+        &self,
+        lower: usize,
+        upper: usize,
+        upper_bound: &Bound,
+        enc: &mut Encoder,
+        out: &mut Vec<u8>,
+    ) {
+        let count = upper - lower;
+        if count < BUCKETS * 2 {
+            enc.bound(upper_bound, out);
+            encode_varint(MODE_ID_LIST, out);
+            encode_varint(count as u64, out);
+            for item in &self.items[lower..upper] {
+                out.extend_from_slice(&item.id);
+            }
+            return;
+        }
+        let per_bucket = count / BUCKETS;
+        let with_extra = count % BUCKETS;
+        let mut curr = lower;
+        for i in 0..BUCKETS {
+            let size = per_bucket + usize::from(i < with_extra);
+            let fp = self.fingerprint(curr, curr + size);
+            curr += size;
+            let bound = if curr == upper {
+                upper_bound.clone()
+            } else {
+                Bound::minimal(&self.items[curr - 1], &self.items[curr])
+            };
+            enc.bound(&bound, out);
+            encode_varint(MODE_FINGERPRINT, out);
+            out.extend_from_slice(&fp);
+        }
+    }
+
+    /// Respond to a message from the initiator.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the message is
+    /// malformed.
+    pub fn reconcile(&self, query: &[u8], frame_size_limit: usize) -> Result<Vec<u8>, String> {
+        let mut dec = Decoder {
+            buf: query,
+            last_timestamp: 0,
+        };
+        let mut enc = Encoder { last_timestamp: 0 };
+        let mut full = vec![PROTOCOL_VERSION];
+        let version = dec.bytes(1)?[0];
+        if !(0x60..=0x6f).contains(&version) {
+            return Err("invalid protocol version".to_owned());
+        }
+        if version != PROTOCOL_VERSION {
+            // tell the client which version we speak
+            return Ok(full);
+        }
+        let budget = frame_size_limit.saturating_sub(FRAME_HEADROOM);
+        let mut prev_bound = Bound::new(0);
+        let mut prev_index = 0;
+        // items covered by the ranges in the response so far
+        let mut covered = 0;
+        let mut skip = false;
+        while !dec.is_empty() {
+            let mut out = vec![];
+            let mut truncated = false;
+            let curr_bound = dec.bound()?;
+            let mode = dec.varint()?;
+            let lower = prev_index;
+            let mut upper = self.lower_bound(prev_index, &curr_bound);
+            match mode {
+                MODE_SKIP => skip = true,
+                MODE_FINGERPRINT => {
+                    let theirs = dec.bytes(FINGERPRINT_SIZE)?;
+                    if theirs == self.fingerprint(lower, upper) {
+                        skip = true;
+                    } else {
+                        flush_skip(&mut skip, &prev_bound, &mut enc, &mut out);
+                        self.split_range(lower, upper, &curr_bound, &mut enc, &mut out);
+                    }
+                }
+                MODE_ID_LIST => {
+                    // the client works out what it has and needs from
+                    // our full list of ids for the range.
+                    let count = dec.varint()?;
+                    for _ in 0..count {
+                        dec.bytes(ID_SIZE)?;
+                    }
+                    flush_skip(&mut skip, &prev_bound, &mut enc, &mut out);
+                    let mut end_bound = curr_bound.clone();
+                    let mut ids = vec![];
+                    for (i, item) in self.items[lower..upper].iter().enumerate() {
+                        if full.len() + out.len() + ids.len() + ID_SIZE > budget {
+                            end_bound = Bound {
+                                timestamp: item.timestamp,
+                                id: item.id.to_vec(),
+                            };
+                            upper = lower + i;
+                            truncated = true;
+                            break;
+                        }
+                        ids.extend_from_slice(&item.id);
+                    }
+                    enc.bound(&end_bound, &mut out);
+                    encode_varint(MODE_ID_LIST, &mut out);
+                    encode_varint((ids.len() / ID_SIZE) as u64, &mut out);
+                    out.extend(ids);
+                }
+                _ => return Err("unexpected mode".to_owned()),
+            }
+            if truncated {
+                // send the ids that fit, and let the client ask again
+                // for the rest.
+                full.append(&mut out);
+                covered = upper;
+            }
+            if truncated || full.len() + out.len() > budget {
+                // summarize everything that did not fit; the client
+                // will follow up with another message.
+                enc.bound(&Bound::new(u64::MAX), &mut full);
+                encode_varint(MODE_FINGERPRINT, &mut full);
+                full.extend_from_slice(&self.fingerprint(covered, self.items.len()));
+                break;
+            }
+            if !out.is_empty() {
+                // skipped ranges are only covered once a later range
+                // is sent.
+                covered = upper;
+            }
+            full.extend(out);
+            prev_index = upper;
+            prev_bound = curr_bound;
+        }
+        Ok(full)
+    }
+}
+
+/// Emit a pending skip, so that the next range starts after it.
+fn flush_skip(skip: &mut bool, prev_bound: &Bound, enc: &mut Encoder, out: &mut Vec<u8>) {
+    if *skip {
+        *skip = false;
+        enc.bound(prev_bound, out);
+        encode_varint(MODE_SKIP, out);
+    }
+}
+
+/// Take the next element of a message array as a string.
+fn next_str<'a, E: serde::de::Error>(
+    i: &mut impl Iterator<Item = &'a Value>,
+    what: &str,
+) -> Result<String, E> {
+    i.next()
+        .and_then(Value::as_str)
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| E::custom(format!("missing {what}")))
+}
+
+/// Check the command name and number of elements of a message array.
+fn message_array<'a, E: serde::de::Error>(
+    v: &'a Value,
+    cmd: &str,
+    len: usize,
+) -> Result<&'a [Value], E> {
+    let va = v.as_array().ok_or_else(|| E::custom("not array"))?;
+    if va.len() != len {
+        return Err(E::custom("wrong number of fields"));
+    }
+    if va[0].as_str() != Some(cmd) {
+        return Err(E::custom(format!("missing {cmd} command")));
+    }
+    Ok(&va[1..])
+}
+
+/// `NEG-OPEN` request, starting a session for a filter.
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegOpen {
+    pub id: String,
+    pub filter: ReqFilter,
+    /// Hex-encoded initial message
+    pub message: String,
+}
+
+impl<'de> Deserialize<'de> for NegOpen {
+    fn deserialize<D>(deserializer: D) -> Result<NegOpen, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v: Value = Deserialize::deserialize(deserializer)?;
+        let mut i = message_array::<D::Error>(&v, "NEG-OPEN", 4)?.iter();
+        let id = next_str::<D::Error>(&mut i, "subscription id")?;
+        let filter: ReqFilter = serde_json::from_value(i.next().unwrap().clone())
+            .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
+        let message = next_str::<D::Error>(&mut i, "message")?;
+        Ok(NegOpen {
+            id,
+            filter,
+            message,
+        })
+    }
+}
+
+/// `NEG-MSG` request, continuing a session.
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegMsg {
+    pub id: String,
+    /// Hex-encoded message
+    pub message: String,
+}
+
+impl<'de> Deserialize<'de> for NegMsg {
+    fn deserialize<D>(deserializer: D) -> Result<NegMsg, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v: Value = Deserialize::deserialize(deserializer)?;
+        let mut i = message_array::<D::Error>(&v, "NEG-MSG", 3)?.iter();
+        let id = next_str::<D::Error>(&mut i, "subscription id")?;
+        let message = next_str::<D::Error>(&mut i, "message")?;
+        Ok(NegMsg { id, message })
+    }
+}
+
+/// `NEG-CLOSE` request, ending a session.
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegClose {
+    pub id: String,
+}
+
+impl<'de> Deserialize<'de> for NegClose {
+    fn deserialize<D>(deserializer: D) -> Result<NegClose, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v: Value = Deserialize::deserialize(deserializer)?;
+        let mut i = message_array::<D::Error>(&v, "NEG-CLOSE", 2)?.iter();
+        let id = next_str::<D::Error>(&mut i, "subscription id")?;
+        Ok(NegClose { id })
+    }
+}
+
+/// Result of the query for a `NEG-OPEN`, and the reply to its
+/// initial message.
+#[derive(Debug)]
+pub struct OpenResult {
+    pub sub_id: String,
+    /// Number of the query, to tell it apart from a later session
+    /// with the same id.
+    pub query_id: u64,
+    /// The session storage and hex-encoded reply, or a `NEG-ERR`
+    /// reason.
+    pub result: Result<(Storage, String), String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(timestamp: u64, n: u8) -> Item {
+        Item {
+            timestamp,
+            id: [n; ID_SIZE],
+        }
+    }
+
+    /// Build an initial message covering everything with a single
+    /// fingerprint, as a client would.
+    fn initial_message(storage: &Storage) -> Vec<u8> {
+        let mut msg = vec![PROTOCOL_VERSION];
+        let mut enc = Encoder { last_timestamp: 0 };
+        enc.bound(&Bound::new(u64::MAX), &mut msg);
+        encode_varint(MODE_FINGERPRINT, &mut msg);
+        msg.extend_from_slice(&storage.fingerprint(0, storage.len()));
+        msg
+    }
+
+    /// Answer a reply as a client without any events would: skip the
+    /// ranges whose ids were sent, collecting the ids, and ask for the
+    /// ids of every range that differs.  Returns `None` once there is
+    /// nothing left to ask for.
+    fn empty_client_reply(reply: &[u8], ids: &mut Vec<[u8; ID_SIZE]>) -> Option<Vec<u8>> {
+        let mut dec = Decoder {
+            buf: &reply[1..],
+            last_timestamp: 0,
+        };
+        let mut enc = Encoder { last_timestamp: 0 };
+        let mut msg = vec![PROTOCOL_VERSION];
+        let empty = Storage::default().fingerprint(0, 0);
+        let mut done = true;
+        while !dec.is_empty() {
+            enc.bound(&dec.bound().unwrap(), &mut msg);
+            match dec.varint().unwrap() {
+                MODE_ID_LIST => {
+                    for _ in 0..dec.varint().unwrap() {
+                        ids.push(dec.bytes(ID_SIZE).unwrap().try_into().unwrap());
+                    }
+                    encode_varint(MODE_SKIP, &mut msg);
+                }
+                MODE_FINGERPRINT if dec.bytes(FINGERPRINT_SIZE).unwrap() != empty => {
+                    encode_varint(MODE_ID_LIST, &mut msg);
+                    encode_varint(0, &mut msg);
+                    done = false;
+                }
+                _ => encode_varint(MODE_SKIP, &mut msg),
+            }
+        }
+        (!done).then_some(msg)
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for n in [0, 1, 127, 128, 300, 16384, u64::MAX] {
+            let mut buf = vec![];
+            encode_varint(n, &mut buf);
+            let mut dec = Decoder {
+                buf: &buf,
+                last_timestamp: 0,
+            };
+            assert_eq!(dec.varint(), Ok(n));
+            assert!(dec.is_empty());
+        }
+        let mut buf = vec![];
+        encode_varint(300, &mut buf);
+        assert_eq!(buf, vec![0x82, 0x2c]);
+    }
+
+    #[test]
+    fn empty_fingerprint() {
+        // sha256 of 32 zero bytes followed by a zero count
+        let fp = Storage::default().fingerprint(0, 0);
+        let expected = sha256::Hash::hash(&[0u8; 33]);
+        assert_eq!(&fp[..], &expected[..FINGERPRINT_SIZE]);
+    }
+
+    #[test]
+    fn fingerprint_ignores_order() {
+        let a = Storage::new(vec![item(1, 1), item(2, 0xff), item(3, 7)]);
+        let b = Storage::new(vec![item(3, 7), item(1, 1), item(2, 0xff), item(1, 1)]);
+        assert_eq!(b.len(), 3);
+        assert_eq!(a.fingerprint(0, 3), b.fingerprint(0, 3));
+        assert_ne!(a.fingerprint(0, 3), a.fingerprint(0, 2));
+    }
+
+    #[test]
+    fn matching_sets_reply_empty() {
+        let storage = Storage::new((0..100).map(|n| item(n, n as u8)).collect());
+        let reply = storage
+            .reconcile(&initial_message(&storage), FRAME_SIZE_LIMIT)
+            .unwrap();
+        assert_eq!(reply, vec![PROTOCOL_VERSION]);
+    }
+
+    #[test]
+    fn small_difference_lists_ids() {
+        let ours = Storage::new((0..10).map(|n| item(n, n as u8)).collect());
+        let theirs = Storage::new((0..9).map(|n| item(n, n as u8)).collect());
+        let reply = ours
+            .reconcile(&initial_message(&theirs), FRAME_SIZE_LIMIT)
+            .unwrap();
+        let mut dec = Decoder {
+            buf: &reply[1..],
+            last_timestamp: 0,
+        };
+        assert_eq!(dec.bound().unwrap(), Bound::new(u64::MAX));
+        assert_eq!(dec.varint(), Ok(MODE_ID_LIST));
+        assert_eq!(dec.varint(), Ok(10));
+        assert_eq!(dec.bytes(10 * ID_SIZE).unwrap().len(), 10 * ID_SIZE);
+        assert!(dec.is_empty());
+    }
+
+    #[test]
+    fn large_difference_splits_range() {
+        let ours = Storage::new((0..1000).map(|n| item(n, (n % 256) as u8)).collect());
+        let reply = ours
+            .reconcile(&initial_message(&Storage::default()), FRAME_SIZE_LIMIT)
+            .unwrap();
+        let mut dec = Decoder {
+            buf: &reply[1..],
+            last_timestamp: 0,
+        };
+        let mut prev = 0;
+        for _ in 0..BUCKETS {
+            let bound = dec.bound().unwrap();
+            assert!(bound.timestamp > prev);
+            prev = bound.timestamp;
+            assert_eq!(dec.varint(), Ok(MODE_FINGERPRINT));
+            dec.bytes(FINGERPRINT_SIZE).unwrap();
+        }
+        assert_eq!(prev, u64::MAX);
+        assert!(dec.is_empty());
+    }
+
+    #[test]
+    fn truncated_id_lists_finish() {
+        let ours = Storage::new((0..1000).map(|n| item(n, (n % 256) as u8)).collect());
+        let frame_size_limit = 4096;
+        // an empty id list for the whole range
+        let mut msg = vec![PROTOCOL_VERSION];
+        Encoder { last_timestamp: 0 }.bound(&Bound::new(u64::MAX), &mut msg);
+        encode_varint(MODE_ID_LIST, &mut msg);
+        encode_varint(0, &mut msg);
+        let mut ids = vec![];
+        let mut rounds = 0;
+        loop {
+            let reply = ours.reconcile(&msg, frame_size_limit).unwrap();
+            assert!(reply.len() <= frame_size_limit);
+            rounds += 1;
+            assert!(rounds < 100, "reconciliation did not finish");
+            match empty_client_reply(&reply, &mut ids) {
+                Some(next) => msg = next,
+                None => break,
+            }
+        }
+        assert!(rounds > 1);
+        let expected: Vec<[u8; ID_SIZE]> = ours.items.iter().map(|i| i.id).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn minimal_bound_uses_id_prefix() {
+        let mut a = item(5, 1);
+        let mut b = item(5, 1);
+        a.id[2] = 3;
+        b.id[2] = 4;
+        let bound = Bound::minimal(&a, &b);
+        assert_eq!(bound.id, vec![1, 1, 4]);
+        assert!(bound.is_after(&a));
+        assert!(!bound.is_after(&b));
+        assert_eq!(Bound::minimal(&item(4, 9), &item(5, 0)), Bound::new(5));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let storage = Storage::default();
+        assert_eq!(
+            storage.reconcile(&[0x62], FRAME_SIZE_LIMIT),
+            Ok(vec![PROTOCOL_VERSION])
+        );
+        assert!(storage.reconcile(&[0x01], FRAME_SIZE_LIMIT).is_err());
+        assert!(storage
+            .reconcile(&[PROTOCOL_VERSION, 0x80], FRAME_SIZE_LIMIT)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_messages() {
+        let open: NegOpen =
+            serde_json::from_str(r#"["NEG-OPEN","s1",{"kinds":[1]},"6100"]"#).unwrap();
+        assert_eq!(open.id, "s1");
+        assert_eq!(open.message, "6100");
+        let msg: NegMsg = serde_json::from_str(r#"["NEG-MSG","s1","61"]"#).unwrap();
+        assert_eq!(msg.message, "61");
+        let close: NegClose = serde_json::from_str(r#"["NEG-CLOSE","s1"]"#).unwrap();
+        assert_eq!(close.id, "s1");
+        assert!(serde_json::from_str::<NegClose>(r#"["CLOSE","s1"]"#).is_err());
+        assert!(serde_json::from_str::<NegMsg>(r#"["NEG-MSG","s1"]"#).is_err());
+    }
+}
@@ -29,6 +29,8 @@ pub struct Verifier {
     event_tx: tokio::sync::broadcast::Sender<Event>,
     /// Settings
     settings: crate::config::Settings,
+    /// Reloaded settings
+    settings_rx: tokio::sync::watch::Receiver<crate::config::Settings>,
     /// HTTP client
     client: hyper::Client<HttpsConnector<HttpConnector>, hyper::Body>,
     /// After all accounts are updated, wait this long before checking again.
@@ -129,7 +131,7 @@ impl Verifier {
         repo: Arc<dyn NostrRepo>,
         metadata_rx: tokio::sync::broadcast::Receiver<Event>,
         event_tx: tokio::sync::broadcast::Sender<Event>,
-        settings: crate::config::Settings,
+        mut settings_rx: tokio::sync::watch::Receiver<crate::config::Settings>,
     ) -> Result<Self> {
         info!("creating NIP-05 verifier");
         // setup hyper client
@@ -151,11 +153,13 @@ impl Verifier {
         // there is no work to be done, it will be reset to a longer
         // duration.
         let reverify_interval = tokio::time::interval(http_wait_duration);
+        let settings = settings_rx.borrow_and_update().clone();
         Ok(Verifier {
             repo,
             metadata_rx,
             event_tx,
             settings,
+            settings_rx,
             client,
             wait_after_finish,
             http_wait_duration,
@@ -281,6 +285,11 @@ impl Verifier {
 
     /// Internal select loop for performing verification
     async fn run_internal(&mut self) -> Result<()> {
+        // pick up reloaded settings
+        if self.settings_rx.has_changed().unwrap_or(false) {
+            self.settings = self.settings_rx.borrow_and_update().clone();
+            debug!("NIP-05 verifier is using reloaded settings");
+        }
         tokio::select! {
             m = self.metadata_rx.recv() => {
                 match m {
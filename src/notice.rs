@@ -6,6 +6,7 @@ pub enum EventResultStatus {
     RateLimited,
     Error,
     Restricted,
+    AuthRequired,
 }
 
 pub struct EventResult {
@@ -14,10 +15,18 @@ pub struct EventResult {
     pub status: EventResultStatus,
 }
 
+/// A subscription that was terminated by the relay (NIP-01 CLOSED)
+pub struct SubscriptionClosed {
+    pub sub_id: String,
+    pub msg: String,
+    pub status: EventResultStatus,
+}
+
 pub enum Notice {
     Message(String),
     EventResult(EventResult),
     AuthChallenge(String),
+    Closed(SubscriptionClosed),
 }
 
 impl EventResultStatus {
@@ -25,7 +34,12 @@ impl EventResultStatus {
     pub fn to_bool(&self) -> bool {
         match self {
             Self::Duplicate | Self::Saved => true,
-            Self::Invalid | Self::Blocked | Self::RateLimited | Self::Error | Self::Restricted => false,
+            Self::Invalid
+            | Self::Blocked
+            | Self::RateLimited
+            | Self::Error
+            | Self::Restricted
+            | Self::AuthRequired => false,
         }
     }
 
@@ -39,6 +53,7 @@ impl EventResultStatus {
             Self::RateLimited => "rate-limited",
             Self::Error => "error",
             Self::Restricted => "restricted",
+            Self::AuthRequired => "auth-required",
         }
     }
 }
@@ -88,6 +103,22 @@ impl Notice {
         Notice::prefixed(id, msg, EventResultStatus::Restricted)
     }
 
+    #[must_use]
+    pub fn auth_required(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::AuthRequired)
+    }
+
+    /// Notify the client that the relay ended a subscription.
+    #[must_use]
+    pub fn closed(sub_id: String, msg: &str, status: EventResultStatus) -> Notice {
+        let msg = format!("{}: {}", status.prefix(), msg);
+        Notice::Closed(SubscriptionClosed {
+            sub_id,
+            msg,
+            status,
+        })
+    }
+
     #[must_use]
     pub fn saved(id: String) -> Notice {
         Notice::EventResult(EventResult {
@@ -1,6 +1,8 @@
 use crate::db::QueryResult;
 use crate::error::Result;
 use crate::event::Event;
+use crate::management::{PolicyEntry, PolicyList};
+use crate::negentropy::Item;
 use crate::nip05::VerificationRecord;
 use crate::payment::{InvoiceInfo, InvoiceStatus};
 use crate::subscription::Subscription;
@@ -28,20 +30,98 @@ pub trait NostrRepo: Send + Sync {
     /// Perform a database query using a subscription.
     ///
     /// The [`Subscription`] is converted into a SQL query.  Each result
-    /// is published on the `query_tx` channel as it is returned.  If a
+    /// is published on the `query_tx` channel as it is returned, tagged
+    /// with `query_id`, which the connection uses to tell this query
+    /// apart from later ones with the same subscription id.  If a
     /// message becomes available on the `abandon_query_rx` channel, the
-    /// query is immediately aborted.
+    /// query is immediately aborted.  Otherwise the query ends with an
+    /// EOSE, or a CLOSED result if the relay stopped it; room is kept
+    /// in the channel for that last result.  The `query_permit` is
+    /// held until the query is finished.
     async fn query_subscription(
         &self,
         sub: Subscription,
+        query_id: u64,
         client_id: String,
         query_tx: tokio::sync::mpsc::Sender<QueryResult>,
         mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+        query_permit: Option<tokio::sync::OwnedSemaphorePermit>,
     ) -> Result<()>;
 
+    /// Count the events matching a subscription (NIP-45).
+    ///
+    /// Each filter of the [`Subscription`] is converted into a SQL
+    /// query exactly as in `query_subscription`, and the distinct
+    /// events matching any filter are counted, so an event matching
+    /// several filters is counted once.
+    async fn count_subscription(&self, sub: Subscription, client_id: String) -> Result<u64>;
+
+    /// Get the timestamp and id of every event matching a
+    /// subscription, for negentropy set reconciliation (NIP-77).  If
+    /// a limit is given, at most that many events are returned for
+    /// each filter.
+    async fn negentropy_items(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<Item>>;
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()>;
 
+    /// Checkpoint the write-ahead log, if the database has one
+    async fn checkpoint_db(&self) -> Result<()>;
+
+    /// Reclaim space left behind by deleted events
+    async fn vacuum_db(&self) -> Result<()>;
+
+    /// Revoke a NIP-26 delegation, and hide any stored events the
+    /// delegatee published on behalf of the delegator.  Returns the
+    /// number of events hidden.
+    async fn revoke_delegation(&self, delegator: &str, delegatee: &str) -> Result<u64>;
+
+    /// Get all revoked delegations, as (delegator, delegatee) pairs.
+    async fn get_delegation_revocations(&self) -> Result<Vec<(String, String)>>;
+
+    /// Record a request to vanish (NIP-62), and delete all events by
+    /// the pubkey created at or before the request, along with any
+    /// gift wraps addressed to it.  Returns the number of events
+    /// deleted.
+    async fn vanish(&self, pubkey: &str, created_at: u64) -> Result<u64>;
+
+    /// Get all vanish requests, as (pubkey, created_at) pairs.
+    async fn get_vanish_requests(&self) -> Result<Vec<(String, u64)>>;
+
+    /// Add an item to a relay management list (NIP-86), replacing
+    /// any existing reason.
+    async fn add_policy_entry(
+        &self,
+        list: PolicyList,
+        item: &str,
+        reason: Option<&str>,
+    ) -> Result<()>;
+
+    /// Remove an item from a relay management list.
+    async fn remove_policy_entry(&self, list: PolicyList, item: &str) -> Result<()>;
+
+    /// Get all relay management list entries.
+    async fn get_policy_entries(&self) -> Result<Vec<PolicyEntry>>;
+
+    /// Store a relay setting changed through the management API.
+    async fn set_relay_setting(&self, name: &str, value: &str) -> Result<()>;
+
+    /// Get all relay settings changed through the management API, as
+    /// (name, value) pairs.
+    async fn get_relay_settings(&self) -> Result<Vec<(String, String)>>;
+
+    /// Hide an event.  Returns the number of events hidden.
+    async fn hide_event(&self, id: &str) -> Result<u64>;
+
+    /// Hide all events by an author, or published under their
+    /// delegation.  Returns the number of events hidden.
+    async fn hide_author_events(&self, pubkey: &str) -> Result<u64>;
+
     /// Create a new verification record connected to a specific event
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()>;
 
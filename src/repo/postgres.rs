@@ -1,10 +1,14 @@
+use crate::config::{Retention, Settings};
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
+use crate::event::Event;
+use crate::management::{PolicyEntry, PolicyList};
+use crate::negentropy::Item;
 use crate::nip05::{Nip05Name, VerificationRecord};
+use crate::notice::EventResultStatus;
 use crate::payment::{InvoiceInfo, InvoiceStatus};
 use crate::repo::{now_jitter, NostrRepo};
-use crate::subscription::{ReqFilter, Subscription};
+use crate::subscription::{search_terms, ReqFilter, Subscription};
 use async_std::stream::StreamExt;
 use async_trait::async_trait;
 use chrono::{DateTime, TimeZone, Utc};
@@ -20,6 +24,7 @@ use crate::utils::{self, is_hex, is_lower_hex};
 use nostr::key::Keys;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot::Receiver;
+use tokio::sync::OwnedSemaphorePermit;
 use tracing::{debug, error, info, trace, warn};
 
 pub type PostgresPool = sqlx::pool::Pool<Postgres>;
@@ -28,14 +33,21 @@ pub struct PostgresRepo {
     conn: PostgresPool,
     conn_write: PostgresPool,
     metrics: NostrMetrics,
+    settings: Settings,
 }
 
 impl PostgresRepo {
-    pub fn new(c: PostgresPool, cw: PostgresPool, m: NostrMetrics) -> PostgresRepo {
+    pub fn new(
+        c: PostgresPool,
+        cw: PostgresPool,
+        settings: &Settings,
+        m: NostrMetrics,
+    ) -> PostgresRepo {
         PostgresRepo {
             conn: c,
             conn_write: cw,
             metrics: m,
+            settings: settings.clone(),
         }
     }
 }
@@ -77,11 +89,174 @@ async fn delete_expired(conn: PostgresPool) -> Result<u64> {
     Ok(update_count)
 }
 
+/// Purge events that have been hidden for the grace period on a
+/// regular basis
+async fn cleanup_hidden(
+    conn: PostgresPool,
+    frequency: Duration,
+    grace: Duration,
+    metrics: NostrMetrics,
+) -> Result<()> {
+    info!(
+        "enabling purge of hidden events (grace period: {:?})",
+        grace
+    );
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    let start = Instant::now();
+                    let before = utils::unix_time().saturating_sub(grace.as_secs());
+                    let purge_res = delete_hidden(conn.clone(), before).await;
+                    match purge_res {
+                        Ok(purge_count) => {
+                            if purge_count > 0 {
+                                metrics.purged_events.inc_by(purge_count);
+                                info!("purged {} hidden events in: {:?}", purge_count, start.elapsed());
+                            }
+                        },
+                        Err(e) => {
+                            warn!("could not purge hidden events due to error: {:?}", e);
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// One-time deletion of all events hidden before a timestamp.  Their
+/// tags are removed by cascade.
+async fn delete_hidden(conn: PostgresPool, before: u64) -> Result<u64> {
+    let mut tx = conn.begin().await?;
+    let delete_count =
+        sqlx::query("DELETE FROM \"event\" WHERE hidden = 1::bit(1) AND hidden_at <= $1;")
+            .bind(Utc.timestamp_opt(before as i64, 0).unwrap())
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+    tx.commit().await?;
+    Ok(delete_count)
+}
+
+/// Prune events according to the retention policy on a regular basis
+async fn cleanup_retention(
+    conn: PostgresPool,
+    frequency: Duration,
+    retention: Retention,
+) -> Result<()> {
+    info!(
+        "enabling retention policy (max_events: {:?}, max_bytes: {:?}, persist_days: {:?})",
+        retention.max_events, retention.max_bytes, retention.persist_days
+    );
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    let start = Instant::now();
+                    let ret_res = delete_for_retention(conn.clone(), &retention).await;
+                    match ret_res {
+                        Ok(ret_count) => {
+                            if ret_count > 0 {
+                                info!("removed {} events due to retention policy in: {:?}", ret_count, start.elapsed());
+                            }
+                        },
+                        Err(e) => {
+                            warn!("could not apply retention policy due to error: {:?}", e);
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// One-time deletion of the oldest events that exceed the retention
+/// policy.  Events from whitelisted authors are never deleted.
+async fn delete_for_retention(conn: PostgresPool, retention: &Retention) -> Result<u64> {
+    let whitelist = retention.whitelist_blobs();
+    let mut tx = conn.begin().await?;
+    let mut delete_count = 0;
+    // remove events older than the persistence window
+    if let Some(days) = retention.persist_days.filter(|d| *d > 0) {
+        let cutoff = utils::unix_time().saturating_sub(days as u64 * 86400);
+        delete_count += sqlx::query(
+            "DELETE FROM \"event\" WHERE created_at < $1 AND pub_key <> ALL($2);",
+        )
+        .bind(Utc.timestamp_opt(cutoff as i64, 0).unwrap())
+        .bind(&whitelist)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    }
+    // remove the oldest events beyond the maximum count
+    if let Some(max_events) = retention.max_events.filter(|m| *m > 0) {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM \"event\";")
+            .fetch_one(&mut tx)
+            .await?;
+        if count > max_events as i64 {
+            delete_count += sqlx::query(
+                "DELETE FROM \"event\" WHERE id IN (SELECT id FROM \"event\" WHERE pub_key <> ALL($1) ORDER BY created_at ASC, id ASC LIMIT $2);",
+            )
+            .bind(&whitelist)
+            .bind(count - max_events as i64)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        }
+    }
+    // remove the oldest events until content fits within the byte limit
+    if let Some(max_bytes) = retention.max_bytes.filter(|m| *m > 0) {
+        let total: i64 =
+            sqlx::query_scalar("SELECT COALESCE(SUM(octet_length(\"content\")), 0)::bigint FROM \"event\";")
+                .fetch_one(&mut tx)
+                .await?;
+        if total > max_bytes as i64 {
+            // running total of event sizes, oldest first; delete rows
+            // until the excess has been covered.
+            delete_count += sqlx::query(
+                "DELETE FROM \"event\" WHERE id IN (SELECT id FROM (SELECT id, octet_length(\"content\") AS size, SUM(octet_length(\"content\")) OVER (ORDER BY created_at ASC, id ASC) AS running FROM \"event\" WHERE pub_key <> ALL($1)) r WHERE running - size < $2);",
+            )
+            .bind(&whitelist)
+            .bind(total - max_bytes as i64)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        }
+    }
+    tx.commit().await?;
+    Ok(delete_count)
+}
+
 #[async_trait]
 impl NostrRepo for PostgresRepo {
     async fn start(&self) -> Result<()> {
         // begin a cleanup task for expired events.
         cleanup_expired(self.conn_write.clone(), Duration::from_secs(600)).await?;
+        // prune events if a retention policy is configured.
+        if self.settings.retention.is_enabled() {
+            cleanup_retention(
+                self.conn_write.clone(),
+                Duration::from_secs(600),
+                self.settings.retention.clone(),
+            )
+            .await?;
+        }
+        // purge hidden events if configured.
+        if self.settings.retention.is_purge_enabled() {
+            cleanup_hidden(
+                self.conn_write.clone(),
+                Duration::from_secs(600),
+                self.settings
+                    .retention
+                    .purge_grace_duration()
+                    .unwrap_or_default(),
+                self.metrics.clone(),
+            )
+            .await?;
+        }
         Ok(())
     }
 
@@ -145,8 +320,8 @@ impl NostrRepo for PostgresRepo {
         // ignore if the event hash is a duplicate.
         let mut ins_count = sqlx::query(
             r#"INSERT INTO "event"
-(id, pub_key, created_at, expires_at, kind, "content", delegated_by)
-VALUES($1, $2, $3, $4, $5, $6, $7)
+(id, pub_key, created_at, expires_at, kind, "content", delegated_by, search_tsv)
+VALUES($1, $2, $3, $4, $5, $6, $7, to_tsvector('simple', $8))
 ON CONFLICT (id) DO NOTHING"#,
         )
         .bind(&id_blob)
@@ -159,6 +334,7 @@ ON CONFLICT (id) DO NOTHING"#,
         .bind(e.kind as i64)
         .bind(event_str.into_bytes())
         .bind(delegator_blob)
+        .bind(&e.content)
         .execute(&mut tx)
         .await?
         .rows_affected();
@@ -175,32 +351,28 @@ ON CONFLICT (id) DO NOTHING"#,
             if tag.len() >= 2 {
                 let tag_name = &tag[0];
                 let tag_val = &tag[1];
-                // only single-char tags are searchable
-                let tag_char_opt = single_char_tagname(tag_name);
-                match &tag_char_opt {
-                    Some(_) => {
-                        // if tag value is lowercase hex;
-                        if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
+                // only single-char and configured tags are searchable
+                if self.settings.options.tag_is_indexed(tag_name) {
+                    // if tag value is lowercase hex;
+                    if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
+                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
                     ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(hex::decode(tag_val).ok())
-                                .execute(&mut tx)
-                                .await
-                                .unwrap();
-                        } else {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
+                            .bind(&id_blob)
+                            .bind(tag_name)
+                            .bind(hex::decode(tag_val).ok())
+                            .execute(&mut tx)
+                            .await
+                            .unwrap();
+                    } else {
+                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
                     ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(tag_val.as_bytes())
-                                .execute(&mut tx)
-                                .await
-                                .unwrap();
-                        }
+                            .bind(&id_blob)
+                            .bind(tag_name)
+                            .bind(tag_val.as_bytes())
+                            .execute(&mut tx)
+                            .await
+                            .unwrap();
                     }
-                    None => {}
                 }
             }
         }
@@ -255,19 +427,55 @@ ON CONFLICT (id) DO NOTHING"#,
                 .filter_map(|x| hex::decode(x).ok())
                 .collect();
 
-            let mut builder = QueryBuilder::new(
-                "UPDATE \"event\" SET hidden = 1::bit(1) WHERE kind != 5 AND pub_key = ",
-            );
-            builder.push_bind(hex::decode(&e.pubkey).ok());
-            builder.push(" AND id IN (");
+            let mut update_count = 0;
+            if !pub_keys.is_empty() {
+                let mut builder = QueryBuilder::new(
+                    "UPDATE \"event\" SET hidden = 1::bit(1), hidden_at = now() WHERE kind != 5 AND pub_key = ",
+                );
+                builder.push_bind(hex::decode(&e.pubkey).ok());
+                builder.push(" AND id IN (");
 
-            let mut sep = builder.separated(", ");
-            for pk in pub_keys {
-                sep.push_bind(pk);
-            }
-            sep.push_unseparated(")");
+                let mut sep = builder.separated(", ");
+                for pk in pub_keys {
+                    sep.push_bind(pk);
+                }
+                sep.push_unseparated(")");
 
-            let update_count = builder.build().execute(&mut tx).await?.rows_affected();
+                update_count += builder.build().execute(&mut tx).await?.rows_affected();
+            }
+            // hide addressable events referenced by `a` tags, up to
+            // the time of the deletion.
+            for (kind, d_tag) in e.deleted_addresses() {
+                let mut builder = QueryBuilder::new(
+                    "UPDATE \"event\" SET hidden = 1::bit(1), hidden_at = now() WHERE kind = ",
+                );
+                builder.push_bind(kind as i64);
+                builder.push(" AND pub_key = ");
+                builder.push_bind(&pubkey_blob);
+                builder.push(" AND created_at <= ");
+                builder.push_bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap());
+                if let Some(d_tag) = d_tag {
+                    // d tags are stored as hex if possible
+                    let d_hex = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+                        hex::decode(&d_tag).ok()
+                    } else {
+                        None
+                    };
+                    builder.push(
+                        " AND (id IN (SELECT event_id FROM tag WHERE \"name\" = 'd' AND (value = ",
+                    );
+                    builder.push_bind(d_tag.as_bytes().to_vec());
+                    builder.push(" OR value_hex = ");
+                    builder.push_bind(d_hex);
+                    builder.push("))");
+                    if d_tag.is_empty() {
+                        builder
+                            .push(" OR id NOT IN (SELECT event_id FROM tag WHERE \"name\" = 'd')");
+                    }
+                    builder.push(")");
+                }
+                update_count += builder.build().execute(&mut tx).await?.rows_affected();
+            }
             info!(
                 "hid {} deleted events for author {:?}",
                 update_count,
@@ -285,6 +493,23 @@ ON CONFLICT (id) DO NOTHING"#,
             .bind(&id_blob)
             .fetch_optional(&mut tx)
             .await?;
+            // addressable events are also deleted by an `a` tag, if
+            // they were not created after the deletion.
+            let del_count = match (del_count, e.address()) {
+                (None, Some(addr)) => {
+                    sqlx::query(
+                        "SELECT e.id FROM \"event\" e \
+                    LEFT JOIN tag t ON e.id = t.event_id \
+                    WHERE e.pub_key = $1 AND t.\"name\" = 'a' AND e.kind = 5 AND t.value = $2 AND e.created_at >= $3 LIMIT 1",
+                    )
+                    .bind(&pubkey_blob)
+                    .bind(addr.as_bytes())
+                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                    .fetch_optional(&mut tx)
+                    .await?
+                }
+                (del_count, _) => del_count,
+            };
 
             // check if a the query returned a result, meaning we should
             // hid the current event
@@ -295,10 +520,12 @@ ON CONFLICT (id) DO NOTHING"#,
                     e.get_event_id_prefix(),
                     e.get_author_prefix()
                 );
-                sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
-                    .bind(&id_blob)
-                    .execute(&mut tx)
-                    .await?;
+                sqlx::query(
+                    "UPDATE \"event\" SET hidden = 1::bit(1), hidden_at = now() WHERE id = $1",
+                )
+                .bind(&id_blob)
+                .execute(&mut tx)
+                .await?;
                 // event was deleted, so let caller know nothing new
                 // arrived, preventing this from being sent to active
                 // subscriptions
@@ -315,10 +542,17 @@ ON CONFLICT (id) DO NOTHING"#,
     async fn query_subscription(
         &self,
         sub: Subscription,
+        query_id: u64,
         client_id: String,
         query_tx: Sender<QueryResult>,
         mut abandon_query_rx: Receiver<()>,
+        _query_permit: Option<OwnedSemaphorePermit>,
     ) -> Result<()> {
+        // keep room for the EOSE or CLOSED that ends the query, even
+        // if the client stops reading results.
+        let Ok(closing) = query_tx.clone().reserve_owned().await else {
+            return Ok(());
+        };
         let start = Instant::now();
         let mut row_count: usize = 0;
         let metrics = &self.metrics;
@@ -355,7 +589,13 @@ ON CONFLICT (id) DO NOTHING"#,
             while let Some(row) = results.next().await {
                 if let Err(e) = row {
                     error!("Query failed: {} {} {:?}", e, sql, filter);
-                    break;
+                    closing.send(QueryResult::closed(
+                        sub.get_id(),
+                        query_id,
+                        EventResultStatus::Error,
+                        "query failed",
+                    ));
+                    return Ok(());
                 }
                 let first_event_elapsed = start.elapsed();
                 slow_first_event = first_event_elapsed >= slow_cutoff;
@@ -408,6 +648,12 @@ ON CONFLICT (id) DO NOTHING"#,
                                 .query_aborts
                                 .with_label_values(&["slowclient"])
                                 .inc();
+                            closing.send(QueryResult::closed(
+                                sub.get_id(),
+                                query_id,
+                                EventResultStatus::RateLimited,
+                                "client is not reading results fast enough",
+                            ));
                             return Ok(());
                         }
                         // give the queue a chance to clear before trying again
@@ -419,22 +665,17 @@ ON CONFLICT (id) DO NOTHING"#,
                 // getting the query result back as part of the error
                 // result.
                 query_tx
-                    .send(QueryResult {
-                        sub_id: sub.get_id(),
-                        event: String::from_utf8(event_json).unwrap(),
-                    })
+                    .send(QueryResult::event(
+                        sub.get_id(),
+                        query_id,
+                        String::from_utf8(event_json).unwrap(),
+                    ))
                     .await
                     .ok();
                 last_successful_send = Instant::now();
             }
         }
-        query_tx
-            .send(QueryResult {
-                sub_id: sub.get_id(),
-                event: "EOSE".to_string(),
-            })
-            .await
-            .ok();
+        closing.send(QueryResult::eose(sub.get_id(), query_id));
         self.metrics
             .query_sub
             .observe(start.elapsed().as_secs_f64());
@@ -449,11 +690,249 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(())
     }
 
+    async fn count_subscription(&self, sub: Subscription, client_id: String) -> Result<u64> {
+        let start = Instant::now();
+        // count the union of all filters, so events matching several
+        // filters are only counted once.  Malformed filters never
+        // match anything.
+        let count = match count_from_filters(&sub.filters) {
+            Some(mut query) => {
+                let count: i64 = query.build().fetch_one(&self.conn).await?.get(0);
+                self.metrics.query_db.observe(start.elapsed().as_secs_f64());
+                count as u64
+            }
+            None => 0,
+        };
+        debug!(
+            "count completed in {:?} (cid: {}, sub: {:?}, count: {})",
+            start.elapsed(),
+            client_id,
+            sub.id,
+            count
+        );
+        Ok(count)
+    }
+
+    async fn negentropy_items(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<Item>> {
+        let start = Instant::now();
+        let mut items: Vec<Item> = vec![];
+        for filter in sub.filters.iter() {
+            let filter_start = Instant::now();
+            let q_filter = ids_from_filter(filter, limit);
+            if q_filter.is_none() {
+                // malformed filters never match anything
+                continue;
+            }
+            let mut q_filter = q_filter.unwrap();
+            let rows = q_filter.build().fetch_all(&self.conn).await?;
+            for row in rows {
+                let id: Vec<u8> = row.get(0);
+                let created_at: DateTime<Utc> = row.get(1);
+                if let Ok(id) = id.try_into() {
+                    items.push(Item {
+                        timestamp: created_at.timestamp() as u64,
+                        id,
+                    });
+                }
+            }
+            self.metrics
+                .query_db
+                .observe(filter_start.elapsed().as_secs_f64());
+        }
+        debug!(
+            "negentropy query completed in {:?} (cid: {}, sub: {:?}, items: {})",
+            start.elapsed(),
+            client_id,
+            sub.id,
+            items.len()
+        );
+        Ok(items)
+    }
+
     async fn optimize_db(&self) -> Result<()> {
         // Not implemented
         Ok(())
     }
 
+    async fn checkpoint_db(&self) -> Result<()> {
+        sqlx::query("CHECKPOINT;").execute(&self.conn_write).await?;
+        Ok(())
+    }
+
+    async fn vacuum_db(&self) -> Result<()> {
+        let start = Instant::now();
+        sqlx::query("VACUUM ANALYZE;")
+            .execute(&self.conn_write)
+            .await?;
+        info!("vacuum ran in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    async fn revoke_delegation(&self, delegator: &str, delegatee: &str) -> Result<u64> {
+        let delegator = hex::decode(delegator).ok();
+        let delegatee = hex::decode(delegatee).ok();
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query(
+            "INSERT INTO delegation_revocation (delegator, delegatee) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(&delegator)
+        .bind(&delegatee)
+        .execute(&mut tx)
+        .await?;
+        let hidden = sqlx::query(
+            "UPDATE \"event\" SET hidden = 1::bit(1), hidden_at = now() WHERE hidden != 1::bit(1) AND delegated_by = $1 AND pub_key = $2",
+        )
+        .bind(&delegator)
+        .bind(&delegatee)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok(hidden)
+    }
+
+    async fn get_delegation_revocations(&self) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
+            "SELECT delegator, delegatee FROM delegation_revocation",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(delegator, delegatee)| (hex::encode(delegator), hex::encode(delegatee)))
+            .collect())
+    }
+
+    async fn vanish(&self, pubkey: &str, created_at: u64) -> Result<u64> {
+        let pubkey_blob = hex::decode(pubkey).ok();
+        let created_at = Utc.timestamp_opt(created_at as i64, 0).unwrap();
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query(
+            "INSERT INTO vanish_request (pubkey, created_at) VALUES ($1, $2) \
+            ON CONFLICT (pubkey) DO UPDATE SET created_at = GREATEST(vanish_request.created_at, excluded.created_at)",
+        )
+        .bind(&pubkey_blob)
+        .bind(created_at)
+        .execute(&mut tx)
+        .await?;
+        // the request itself is kept, to refuse older events
+        let authored = sqlx::query(
+            "DELETE FROM \"event\" WHERE pub_key = $1 AND created_at <= $2 AND kind != 62",
+        )
+        .bind(&pubkey_blob)
+        .bind(created_at)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        let gift_wraps = sqlx::query(
+            "DELETE FROM \"event\" WHERE kind = 1059 AND id IN (SELECT event_id FROM tag WHERE \"name\" = 'p' AND value_hex = $1)",
+        )
+        .bind(&pubkey_blob)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok(authored + gift_wraps)
+    }
+
+    async fn get_vanish_requests(&self) -> Result<Vec<(String, u64)>> {
+        let rows = sqlx::query_as::<_, (Vec<u8>, DateTime<Utc>)>(
+            "SELECT pubkey, created_at FROM vanish_request",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(pubkey, created_at)| (hex::encode(pubkey), created_at.timestamp() as u64))
+            .collect())
+    }
+
+    async fn add_policy_entry(
+        &self,
+        list: PolicyList,
+        item: &str,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO relay_policy (list, item, reason) VALUES ($1, $2, $3) ON CONFLICT (list, item) DO UPDATE SET reason = EXCLUDED.reason",
+        )
+        .bind(list.name())
+        .bind(item)
+        .bind(reason)
+        .execute(&self.conn_write)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_policy_entry(&self, list: PolicyList, item: &str) -> Result<()> {
+        sqlx::query("DELETE FROM relay_policy WHERE list = $1 AND item = $2")
+            .bind(list.name())
+            .bind(item)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_policy_entries(&self) -> Result<Vec<PolicyEntry>> {
+        let rows = sqlx::query_as::<_, (String, String, Option<String>)>(
+            "SELECT list, item, reason FROM relay_policy",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        // ignore lists this version does not know about
+        Ok(rows
+            .into_iter()
+            .filter_map(|(list, item, reason)| {
+                PolicyList::from_name(&list).map(|list| PolicyEntry { list, item, reason })
+            })
+            .collect())
+    }
+
+    async fn set_relay_setting(&self, name: &str, value: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO relay_setting (name, value) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET value = EXCLUDED.value",
+        )
+        .bind(name)
+        .bind(value)
+        .execute(&self.conn_write)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_relay_settings(&self) -> Result<Vec<(String, String)>> {
+        let rows = sqlx::query_as::<_, (String, String)>("SELECT name, value FROM relay_setting")
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows)
+    }
+
+    async fn hide_event(&self, id: &str) -> Result<u64> {
+        let hidden = sqlx::query(
+            "UPDATE \"event\" SET hidden = 1::bit(1), hidden_at = now() WHERE hidden != 1::bit(1) AND id = $1",
+        )
+        .bind(hex::decode(id).ok())
+        .execute(&self.conn_write)
+        .await?
+        .rows_affected();
+        Ok(hidden)
+    }
+
+    async fn hide_author_events(&self, pubkey: &str) -> Result<u64> {
+        let hidden = sqlx::query(
+            "UPDATE \"event\" SET hidden = 1::bit(1), hidden_at = now() WHERE hidden != 1::bit(1) AND (pub_key = $1 OR delegated_by = $1)",
+        )
+        .bind(hex::decode(pubkey).ok())
+        .execute(&self.conn_write)
+        .await?
+        .rows_affected();
+        Ok(hidden)
+    }
+
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
         let mut tx = self.conn_write.begin().await?;
 
@@ -716,14 +1195,86 @@ LIMIT 1;
     }
 }
 
+/// Columns produced by a filter query.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FilterSelect {
+    /// Event content, for sending to subscribers.
+    Content,
+    /// Event ids and timestamps, for set reconciliation.
+    Ids,
+}
+
 /// Create a dynamic SQL query and params from a subscription filter.
 fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
+    build_filter_query(f, FilterSelect::Content)
+}
+
+/// Create a dynamic SQL query counting the distinct events that
+/// match any of the subscription filters.
+fn count_from_filters(filters: &[ReqFilter]) -> Option<QueryBuilder<'_, Postgres>> {
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM (");
+    let mut any_filter = false;
+    for f in filters {
+        // skip filters that can never match
+        if build_filter_query(f, FilterSelect::Ids).is_none() {
+            continue;
+        }
+        if any_filter {
+            query.push(" UNION ");
+        }
+        query.push("(");
+        push_filter_query(&mut query, f, FilterSelect::Ids);
+        query.push(")");
+        any_filter = true;
+    }
+    query.push(") c");
+    any_filter.then_some(query)
+}
+
+/// Create a dynamic SQL query selecting the id and timestamp of
+/// events that match a subscription filter, returning at most `limit`
+/// rows if given.
+fn ids_from_filter(f: &ReqFilter, limit: Option<usize>) -> Option<QueryBuilder<'_, Postgres>> {
+    let Some(limit) = limit else {
+        return build_filter_query(f, FilterSelect::Ids);
+    };
+    let mut query = QueryBuilder::new("SELECT id, created_at FROM (");
+    if !push_filter_query(&mut query, f, FilterSelect::Ids) {
+        return None;
+    }
+    query.push(") n LIMIT ");
+    query.push(limit);
+    Some(query)
+}
+
+/// Create a dynamic SQL query for a subscription filter, selecting
+/// the columns given by `select`.
+fn build_filter_query(f: &ReqFilter, select: FilterSelect) -> Option<QueryBuilder<'_, Postgres>> {
+    let mut query = QueryBuilder::new("");
+    push_filter_query(&mut query, f, select).then_some(query)
+}
+
+/// Append a query for a subscription filter to `query`, selecting
+/// the columns given by `select`.  Returns false if the filter can
+/// never match, in which case `query` should be discarded.
+fn push_filter_query<'a>(
+    query: &mut QueryBuilder<'a, Postgres>,
+    f: &'a ReqFilter,
+    select: FilterSelect,
+) -> bool {
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
-        return None;
+        return false;
     }
 
-    let mut query = QueryBuilder::new("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+    match select {
+        FilterSelect::Content => {
+            query.push("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+        }
+        FilterSelect::Ids => {
+            query.push("SELECT e.id, e.created_at FROM \"event\" e WHERE ");
+        }
+    };
 
     // This tracks whether we need to push a prefix AND before adding another clause
     let mut push_and = false;
@@ -733,7 +1284,7 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         let auth_vec: Vec<&String> = auth_vec.iter().filter(|a| is_hex(a)).collect();
 
         if auth_vec.is_empty() {
-            return None;
+            return false;
         }
         query.push("(e.pub_key in (");
 
@@ -753,7 +1304,7 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
     // Query for Kind
     if let Some(ks) = &f.kinds {
         if ks.is_empty() {
-            return None;
+            return false;
         }
         if push_and {
             query.push(" AND ");
@@ -773,7 +1324,7 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         // filter out non-hex values
         let id_vec: Vec<&String> = id_vec.iter().filter(|a| is_hex(a)).collect();
         if id_vec.is_empty() {
-            return None;
+            return false;
         }
         if push_and {
             query.push(" AND (");
@@ -802,7 +1353,7 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
             query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and ");
             for (key, val) in map.iter() {
                 if val.is_empty() {
-                    return None;
+                    return false;
                 }
                 if push_or {
                     query.push(" OR ");
@@ -841,6 +1392,21 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         }
     }
 
+    // Query for full-text search
+    if let Some(search) = &f.search {
+        let terms = search_terms(search);
+        if !terms.is_empty() {
+            if push_and {
+                query.push(" AND ");
+            }
+            push_and = true;
+            query
+                .push("e.search_tsv @@ plainto_tsquery('simple', ")
+                .push_bind(terms.join(" "))
+                .push(")");
+        }
+    }
+
     // Query for timestamp
     if f.since.is_some() {
         if push_and {
@@ -872,6 +1438,14 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
     // never display expired events
     query.push(" AND (e.expires_at IS NULL OR e.expires_at > now())");
 
+    // Ids are not capped, but still respect an explicit limit.
+    if select == FilterSelect::Ids {
+        if let Some(lim) = f.limit {
+            query.push(" ORDER BY e.created_at DESC LIMIT ");
+            query.push(lim);
+        }
+        return true;
+    }
     // Apply per-filter limit to this query.
     // The use of a LIMIT implies a DESC order, to capture only the most recent events.
     if let Some(lim) = f.limit {
@@ -881,7 +1455,7 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         query.push(" ORDER BY e.created_at ASC LIMIT ");
         query.push(1000);
     }
-    Some(query)
+    true
 }
 
 impl FromRow<'_, PgRow> for VerificationRecord {
@@ -923,11 +1497,12 @@ mod tests {
             ]),
             limit: None,
             tags: Some(HashMap::from([(
-                'p',
+                "p".to_owned(),
                 HashSet::from([
                     "63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed".to_owned(),
                 ]),
             )])),
+            search: None,
             force_no_match: false,
         };
 
@@ -935,6 +1510,45 @@ mod tests {
         assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value_hex in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
     }
 
+    #[test]
+    fn test_count_gen_kind() {
+        let filter = ReqFilter {
+            ids: None,
+            kinds: Some(vec![1000]),
+            since: None,
+            until: None,
+            authors: None,
+            limit: None,
+            tags: None,
+            search: None,
+            force_no_match: false,
+        };
+
+        let filters = [filter];
+        let q = count_from_filters(&filters).unwrap();
+        assert_eq!(q.sql(), "SELECT COUNT(*) FROM ((SELECT e.id, e.created_at FROM \"event\" e WHERE e.kind in ($1) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()))) c")
+    }
+
+    #[test]
+    fn test_count_gen_union() {
+        let kinds = |k| ReqFilter {
+            ids: None,
+            kinds: Some(vec![k]),
+            since: None,
+            until: None,
+            authors: None,
+            limit: None,
+            tags: None,
+            search: None,
+            force_no_match: false,
+        };
+        let mut never = kinds(1);
+        never.force_no_match = true;
+        let filters = [kinds(1), never, kinds(2)];
+        let q = count_from_filters(&filters).unwrap();
+        assert_eq!(q.sql(), "SELECT COUNT(*) FROM ((SELECT e.id, e.created_at FROM \"event\" e WHERE e.kind in ($1) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now())) UNION (SELECT e.id, e.created_at FROM \"event\" e WHERE e.kind in ($2) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()))) c")
+    }
+
     #[test]
     fn test_query_gen_tag_value() {
         let filter = ReqFilter {
@@ -946,7 +1560,8 @@ mod tests {
                 "84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned(),
             ]),
             limit: None,
-            tags: Some(HashMap::from([('d', HashSet::from(["test".to_owned()]))])),
+            tags: Some(HashMap::from([("d".to_owned(), HashSet::from(["test".to_owned()]))])),
+            search: None,
             force_no_match: false,
         };
 
@@ -966,12 +1581,13 @@ mod tests {
             ]),
             limit: None,
             tags: Some(HashMap::from([(
-                'd',
+                "d".to_owned(),
                 HashSet::from([
                     "test".to_owned(),
                     "63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed".to_owned(),
                 ]),
             )])),
+            search: None,
             force_no_match: false,
         };
 
@@ -989,9 +1605,10 @@ mod tests {
             authors: None,
             limit: None,
             tags: Some(HashMap::from([
-                ('d', HashSet::from(["follow".to_owned()])),
-                ('t', HashSet::from(["siamstr".to_owned()])),
+                ("d".to_owned(), HashSet::from(["follow".to_owned()])),
+                ("t".to_owned(), HashSet::from(["siamstr".to_owned()])),
             ])),
+            search: None,
             force_no_match: false,
         };
         let q = query_from_filter(&filter).unwrap();
@@ -1007,7 +1624,8 @@ mod tests {
             until: None,
             authors: None,
             limit: None,
-            tags: Some(HashMap::from([('a', HashSet::new())])),
+            tags: Some(HashMap::from([("a".to_owned(), HashSet::new())])),
+            search: None,
             force_no_match: false,
         };
         assert!(query_from_filter(&filter).is_none());
@@ -37,6 +37,11 @@ pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
     run_migration(m003::migration(), db).await;
     run_migration(m004::migration(), db).await;
     run_migration(m005::migration(), db).await;
+    run_migration(m006::migration(), db).await;
+    run_migration(m007::migration(), db).await;
+    run_migration(m008::migration(), db).await;
+    run_migration(m009::migration(), db).await;
+    run_migration(m010::migration(), db).await;
     Ok(current_version(db).await as usize)
 }
 
@@ -318,3 +323,119 @@ CREATE TABLE "invoice" (
         }
     }
 }
+
+mod m006 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Full-text search vector of event content (NIP-50)
+ALTER TABLE "event" ADD COLUMN search_tsv tsvector;
+UPDATE "event" SET search_tsv = to_tsvector('simple', convert_from("content", 'UTF8')::jsonb->>'content');
+-- Index search vector
+CREATE INDEX event_search_tsv_idx ON "event" USING GIN (search_tsv);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Revoked delegations (NIP-26)
+CREATE TABLE "delegation_revocation" (
+	delegator bytea NOT NULL,
+	delegatee bytea NOT NULL,
+	revoked_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT delegation_revocation_pkey PRIMARY KEY (delegator, delegatee)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Time an event was hidden, for purging hidden events
+ALTER TABLE "event" ADD COLUMN hidden_at timestamp with time zone NULL;
+UPDATE "event" SET hidden_at = now() WHERE hidden = 1::bit(1);
+CREATE INDEX event_hidden_at_idx ON "event" (hidden_at) WHERE hidden_at IS NOT NULL;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m009 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 9;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Requests to vanish (NIP-62)
+CREATE TABLE "vanish_request" (
+	pubkey bytea NOT NULL,
+	created_at timestamp with time zone NOT NULL,
+	CONSTRAINT vanish_request_pkey PRIMARY KEY (pubkey)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m010 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 10;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Relay management lists (NIP-86)
+CREATE TABLE "relay_policy" (
+	list text NOT NULL,
+	item text NOT NULL,
+	reason text NULL,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT relay_policy_pkey PRIMARY KEY (list, item)
+);
+-- Relay settings changed through the management API (NIP-86)
+CREATE TABLE "relay_setting" (
+	name text NOT NULL,
+	value text NOT NULL,
+	CONSTRAINT relay_setting_pkey PRIMARY KEY (name)
+);
+        "#,
+            ],
+        }
+    }
+}
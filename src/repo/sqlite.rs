@@ -1,14 +1,17 @@
 //! Event persistence and querying
 //use crate::config::SETTINGS;
-use crate::config::Settings;
+use crate::config::{Options, Retention, Settings};
 use crate::db::QueryResult;
 use crate::error::{Error::SqlError, Result};
-use crate::event::{single_char_tagname, Event};
+use crate::event::Event;
+use crate::management::{PolicyEntry, PolicyList};
+use crate::negentropy::Item;
 use crate::nip05::{Nip05Name, VerificationRecord};
+use crate::notice::EventResultStatus;
 use crate::payment::{InvoiceInfo, InvoiceStatus};
 use crate::repo::sqlite_migration::{upgrade_db, STARTUP_SQL};
 use crate::server::NostrMetrics;
-use crate::subscription::{ReqFilter, Subscription};
+use crate::subscription::{search_terms, ReqFilter, Subscription};
 use crate::utils::{is_hex, unix_time};
 use async_trait::async_trait;
 use hex;
@@ -23,7 +26,7 @@ use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
-use tokio::sync::{Mutex, MutexGuard, Semaphore};
+use tokio::sync::{Mutex, MutexGuard, OwnedSemaphorePermit, Semaphore};
 use tokio::task;
 use tracing::{debug, info, trace, warn};
 
@@ -50,6 +53,8 @@ pub struct SqliteRepo {
     write_in_progress: Arc<Mutex<u64>>,
     /// Semaphore for readers to acquire blocking threads
     reader_threads_ready: Arc<Semaphore>,
+    /// Relay settings
+    settings: Settings,
 }
 
 impl SqliteRepo {
@@ -98,11 +103,16 @@ impl SqliteRepo {
             checkpoint_in_progress,
             write_in_progress,
             reader_threads_ready,
+            settings: settings.clone(),
         }
     }
 
     /// Persist an event to the database, returning rows added.
-    pub fn persist_event(conn: &mut PooledConnection, e: &Event) -> Result<u64> {
+    pub fn persist_event(
+        conn: &mut PooledConnection,
+        e: &Event,
+        options: &Options,
+    ) -> Result<u64> {
         // enable auto vacuum
         conn.execute_batch("pragma auto_vacuum = FULL")?;
 
@@ -154,16 +164,12 @@ impl SqliteRepo {
             if tag.len() >= 2 {
                 let tagname = &tag[0];
                 let tagval = &tag[1];
-                // only single-char tags are searchable
-                let tagchar_opt = single_char_tagname(tagname);
-                match &tagchar_opt {
-                    Some(_) => {
-                        tx.execute(
-                            "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-                            params![ev_id, &tagname, &tagval, e.kind, e.created_at],
-                        )?;
-                    }
-                    None => {}
+                // only single-char and configured tags are searchable
+                if options.tag_is_indexed(tagname) {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![ev_id, &tagname, &tagval, e.kind, e.created_at],
+                    )?;
                 }
             }
         }
@@ -211,11 +217,26 @@ impl SqliteRepo {
                 .filter_map(|x| hex::decode(x).ok())
                 .for_each(|x| params.push(Box::new(x)));
             let query = format!(
-                "UPDATE event SET hidden=TRUE WHERE kind!=5 AND author=? AND event_hash IN ({})",
+                "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE kind!=5 AND author=? AND event_hash IN ({})",
                 repeat_vars(params.len() - 1)
             );
             let mut stmt = tx.prepare(&query)?;
-            let update_count = stmt.execute(rusqlite::params_from_iter(params))?;
+            let mut update_count = stmt.execute(rusqlite::params_from_iter(params))?;
+            // hide addressable events referenced by `a` tags, up to
+            // the time of the deletion.
+            for (kind, d_tag) in e.deleted_addresses() {
+                update_count += if let Some(d_tag) = d_tag {
+                    tx.execute(
+                        "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE kind=?1 AND author=?2 AND created_at<=?3 AND (id IN (SELECT event_id FROM tag WHERE name='d' AND value=?4) OR (?4='' AND id NOT IN (SELECT event_id FROM tag WHERE name='d')));",
+                        params![kind, pubkey_blob, e.created_at, d_tag],
+                    )?
+                } else {
+                    tx.execute(
+                        "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE kind=? AND author=? AND created_at<=?;",
+                        params![kind, pubkey_blob, e.created_at],
+                    )?
+                };
+            }
             info!(
                 "hid {} deleted events for author {:?}",
                 update_count,
@@ -224,9 +245,16 @@ impl SqliteRepo {
         } else {
             // check if a deletion has already been recorded for this event.
             // Only relevant for non-deletion events
-            let del_count = tx.query_row(
+            let mut del_count = tx.query_row(
                 "SELECT e.id FROM event e WHERE e.author=? AND e.id IN (SELECT t.event_id FROM tag t WHERE t.name='e' AND t.kind=5 AND t.value=?) LIMIT 1;",
                 params![pubkey_blob, e.id], |row| row.get::<usize, usize>(0));
+            // addressable events are also deleted by an `a` tag, if
+            // they were not created after the deletion.
+            if let (Err(_), Some(addr)) = (&del_count, e.address()) {
+                del_count = tx.query_row(
+                    "SELECT e.id FROM event e WHERE e.author=? AND e.id IN (SELECT t.event_id FROM tag t WHERE t.name='a' AND t.kind=5 AND t.value=? AND t.created_at>=?) LIMIT 1;",
+                    params![pubkey_blob, addr, e.created_at], |row| row.get::<usize, usize>(0));
+            }
             // check if a the query returned a result, meaning we should
             // hid the current event
             if del_count.ok().is_some() {
@@ -236,8 +264,10 @@ impl SqliteRepo {
                     e.get_event_id_prefix(),
                     e.get_author_prefix()
                 );
-                let _update_count =
-                    tx.execute("UPDATE event SET hidden=TRUE WHERE id=?", params![ev_id])?;
+                let _update_count = tx.execute(
+                    "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE id=?",
+                    params![ev_id],
+                )?;
                 // event was deleted, so let caller know nothing new
                 // arrived, preventing this from being sent to active
                 // subscriptions
@@ -264,7 +294,30 @@ impl NostrRepo for SqliteRepo {
             Duration::from_secs(600),
             self.write_in_progress.clone(),
         )
-        .await
+        .await?;
+        if self.settings.retention.is_enabled() {
+            cleanup_retention(
+                self.maint_pool.clone(),
+                Duration::from_secs(600),
+                self.write_in_progress.clone(),
+                self.settings.retention.clone(),
+            )
+            .await?;
+        }
+        if self.settings.retention.is_purge_enabled() {
+            cleanup_hidden(
+                self.maint_pool.clone(),
+                Duration::from_secs(600),
+                self.write_in_progress.clone(),
+                self.settings
+                    .retention
+                    .purge_grace_duration()
+                    .unwrap_or_default(),
+                self.metrics.clone(),
+            )
+            .await?;
+        }
+        Ok(())
     }
 
     async fn migrate_up(&self) -> Result<usize> {
@@ -281,6 +334,7 @@ impl NostrRepo for SqliteRepo {
         // spawn a blocking thread
         //let mut conn = self.write_pool.get()?;
         let pool = self.write_pool.clone();
+        let options = self.settings.options.clone();
         let e = e.clone();
         let event_count = task::spawn_blocking(move || {
             let mut conn = pool.get()?;
@@ -288,7 +342,7 @@ impl NostrRepo for SqliteRepo {
             // multiple times before giving up.
             loop {
                 attempts += 1;
-                let wr = SqliteRepo::persist_event(&mut conn, &e);
+                let wr = SqliteRepo::persist_event(&mut conn, &e, &options);
                 match wr {
                     Err(SqlError(rusqlite::Error::SqliteFailure(e, _))) => {
                         // this basically means that NIP-05 or another
@@ -325,9 +379,11 @@ impl NostrRepo for SqliteRepo {
     async fn query_subscription(
         &self,
         sub: Subscription,
+        query_id: u64,
         client_id: String,
         query_tx: tokio::sync::mpsc::Sender<QueryResult>,
         mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+        query_permit: Option<OwnedSemaphorePermit>,
     ) -> Result<()> {
         let pre_spawn_start = Instant::now();
         // if we let every request spawn a thread, we'll exhaust the
@@ -340,9 +396,19 @@ impl NostrRepo for SqliteRepo {
             .acquire_owned()
             .await
             .unwrap();
+        // keep room for the EOSE or CLOSED that ends the query, even
+        // if the client stops reading results.
+        let Ok(closing) = query_tx.clone().reserve_owned().await else {
+            return Ok(());
+        };
         let self = self.clone();
         let metrics = self.metrics.clone();
-        task::spawn_blocking(move || {
+        let closed_tx = query_tx.clone();
+        let closed_sub_id = sub.get_id();
+        let closed_client_id = client_id.clone();
+        let query = task::spawn_blocking(move || {
+            // hold the client's query permit until this thread exits
+            let _query_permit = query_permit;
             {
                 // if we are waiting on a checkpoint, stop until it is complete
                 let _x = self.checkpoint_in_progress.blocking_lock();
@@ -355,6 +421,12 @@ impl NostrRepo for SqliteRepo {
                     db_queue_time, client_id, sub.id
                 );
                 metrics.query_aborts.with_label_values(&["loadshed"]).inc();
+                closing.send(QueryResult::closed(
+                    sub.get_id(),
+                    query_id,
+                    EventResultStatus::Error,
+                    "relay is overloaded, try again later",
+                ));
                 return Ok(());
             }
             // otherwise, report queuing time if it is slow
@@ -440,6 +512,12 @@ impl NostrRepo for SqliteRepo {
                                         .query_aborts
                                         .with_label_values(&["checkpoint"])
                                         .inc();
+                                    closing.send(QueryResult::closed(
+                                        sub.get_id(),
+                                        query_id,
+                                        EventResultStatus::Error,
+                                        "query interrupted by database maintenance, try again",
+                                    ));
                                     return Ok(());
                                 }
                             }
@@ -470,8 +548,13 @@ impl NostrRepo for SqliteRepo {
                                     .query_aborts
                                     .with_label_values(&["slowclient"])
                                     .inc();
-                                let ok: Result<()> = Ok(());
-                                return ok;
+                                closing.send(QueryResult::closed(
+                                    sub.get_id(),
+                                    query_id,
+                                    EventResultStatus::RateLimited,
+                                    "client is not reading results fast enough",
+                                ));
+                                return Ok(());
                             }
                             // check if a checkpoint is trying to run, and abort
                             if self.checkpoint_in_progress.try_lock().is_err() {
@@ -484,6 +567,12 @@ impl NostrRepo for SqliteRepo {
                                     .query_aborts
                                     .with_label_values(&["checkpoint"])
                                     .inc();
+                                closing.send(QueryResult::closed(
+                                    sub.get_id(),
+                                    query_id,
+                                    EventResultStatus::Error,
+                                    "query interrupted by database maintenance, try again",
+                                ));
                                 return Ok(());
                             }
                             // give the queue a chance to clear before trying again
@@ -497,10 +586,7 @@ impl NostrRepo for SqliteRepo {
                         // getting the query result back as part of the error
                         // result.
                         query_tx
-                            .blocking_send(QueryResult {
-                                sub_id: sub.get_id(),
-                                event: event_json,
-                            })
+                            .blocking_send(QueryResult::event(sub.get_id(), query_id, event_json))
                             .ok();
                         last_successful_send = Instant::now();
                     }
@@ -520,6 +606,13 @@ impl NostrRepo for SqliteRepo {
                 }
             } else {
                 warn!("Could not get a database connection for querying");
+                closing.send(QueryResult::closed(
+                    sub.get_id(),
+                    query_id,
+                    EventResultStatus::Error,
+                    "could not connect to the database",
+                ));
+                return Ok(());
             }
             drop(sem); // new query can begin
             debug!(
@@ -530,21 +623,140 @@ impl NostrRepo for SqliteRepo {
                 start.elapsed(),
                 row_count
             );
-            query_tx
-                .blocking_send(QueryResult {
-                    sub_id: sub.get_id(),
-                    event: "EOSE".to_string(),
-                })
-                .ok();
+            closing.send(QueryResult::eose(sub.get_id(), query_id));
             metrics
                 .query_sub
                 .observe(pre_spawn_start.elapsed().as_secs_f64());
             let ok: Result<()> = Ok(());
             ok
         });
+        // a query that failed part way through never sent EOSE; tell
+        // the client it was closed.
+        task::spawn(async move {
+            if let Ok(Err(e)) = query.await {
+                warn!(
+                    "query failed: {:?} (cid: {}, sub: {:?})",
+                    e, closed_client_id, closed_sub_id
+                );
+                closed_tx
+                    .send(QueryResult::closed(
+                        closed_sub_id,
+                        query_id,
+                        EventResultStatus::Error,
+                        "query failed",
+                    ))
+                    .await
+                    .ok();
+            }
+        });
         Ok(())
     }
 
+    /// Count the events matching a subscription (NIP-45).
+    async fn count_subscription(&self, sub: Subscription, client_id: String) -> Result<u64> {
+        let start = Instant::now();
+        // share the reader thread limit with regular queries.
+        let sem = self
+            .reader_threads_ready
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let self = self.clone();
+        let metrics = self.metrics.clone();
+        let sub_id = sub.get_id();
+        let count = task::spawn_blocking(move || {
+            {
+                // if we are waiting on a checkpoint, stop until it is complete
+                let _x = self.checkpoint_in_progress.blocking_lock();
+            }
+            let mut conn = self.read_pool.get()?;
+            let query_start = Instant::now();
+            let (count_q, params) = count_from_filters(&sub.filters);
+            conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
+            let mut stmt = conn.prepare_cached(&count_q)?;
+            let count: u64 = stmt.query_row(rusqlite::params_from_iter(params), |r| r.get(0))?;
+            metrics
+                .query_db
+                .observe(query_start.elapsed().as_secs_f64());
+            drop(sem); // new query can begin
+            let ok: Result<u64> = Ok(count);
+            ok
+        })
+        .await??;
+        debug!(
+            "count completed in {:?} (cid: {}, sub: {:?}, count: {})",
+            start.elapsed(),
+            client_id,
+            sub_id,
+            count
+        );
+        Ok(count)
+    }
+
+    async fn negentropy_items(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<Item>> {
+        let start = Instant::now();
+        // share the reader thread limit with regular queries.
+        let sem = self
+            .reader_threads_ready
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let self = self.clone();
+        let metrics = self.metrics.clone();
+        let sub_id = sub.get_id();
+        let items = task::spawn_blocking(move || {
+            {
+                // if we are waiting on a checkpoint, stop until it is complete
+                let _x = self.checkpoint_in_progress.blocking_lock();
+            }
+            let mut conn = self.read_pool.get()?;
+            let mut items: Vec<Item> = vec![];
+            for filter in sub.filters.iter() {
+                let filter_start = Instant::now();
+                let (q, p, _idx) = query_from_filter(filter);
+                let mut ids_q = format!("SELECT created_at, event_hash FROM ({q})");
+                if let Some(limit) = limit {
+                    ids_q.push_str(&format!(" LIMIT {limit}"));
+                }
+                conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
+                let mut stmt = conn.prepare_cached(&ids_q)?;
+                let mut rows = stmt.query(rusqlite::params_from_iter(p))?;
+                while let Some(row) = rows.next()? {
+                    let created_at: u64 = row.get(0)?;
+                    let event_hash: Vec<u8> = row.get(1)?;
+                    if let Ok(id) = event_hash.try_into() {
+                        items.push(Item {
+                            timestamp: created_at,
+                            id,
+                        });
+                    }
+                }
+                metrics
+                    .query_db
+                    .observe(filter_start.elapsed().as_secs_f64());
+            }
+            drop(sem); // new query can begin
+            let ok: Result<Vec<Item>> = Ok(items);
+            ok
+        })
+        .await??;
+        debug!(
+            "negentropy query completed in {:?} (cid: {}, sub: {:?}, items: {})",
+            start.elapsed(),
+            client_id,
+            sub_id,
+            items.len()
+        );
+        Ok(items)
+    }
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()> {
         let conn = self.write_pool.get()?;
@@ -557,6 +769,250 @@ impl NostrRepo for SqliteRepo {
         Ok(())
     }
 
+    /// Checkpoint and truncate the WAL
+    async fn checkpoint_db(&self) -> Result<()> {
+        let mut conn = self.maint_pool.get()?;
+        // block writers and new readers until the checkpoint completes
+        let _write_guard = self.write_in_progress.lock().await;
+        let _checkpoint_guard = self.checkpoint_in_progress.lock().await;
+        task::spawn_blocking(move || checkpoint_db(&mut conn).map(|_| ())).await?
+    }
+
+    /// Rebuild the database file
+    async fn vacuum_db(&self) -> Result<()> {
+        let conn = self.maint_pool.get()?;
+        // block writers and new readers until the vacuum completes
+        let _write_guard = self.write_in_progress.lock().await;
+        let _checkpoint_guard = self.checkpoint_in_progress.lock().await;
+        task::spawn_blocking(move || {
+            let start = Instant::now();
+            conn.execute_batch("VACUUM;")?;
+            info!("vacuum ran in {:?}", start.elapsed());
+            let ok: Result<()> = Ok(());
+            ok
+        })
+        .await?
+    }
+
+    /// Revoke a delegation and hide events published under it
+    async fn revoke_delegation(&self, delegator: &str, delegatee: &str) -> Result<u64> {
+        let delegator_blob = hex::decode(delegator).ok();
+        let delegatee_blob = hex::decode(delegatee).ok();
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let hidden_count: u64;
+            {
+                tx.execute(
+                    "INSERT OR IGNORE INTO delegation_revocation (delegator, delegatee, revoked_at) VALUES (?1, ?2, strftime('%s','now'));",
+                    params![delegator_blob, delegatee_blob],
+                )?;
+                hidden_count = tx.execute(
+                    "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE hidden!=TRUE AND delegated_by=?1 AND author=?2;",
+                    params![delegator_blob, delegatee_blob],
+                )? as u64;
+            }
+            tx.commit()?;
+            let ok: Result<u64> = Ok(hidden_count);
+            ok
+        })
+        .await?
+    }
+
+    /// Get all revoked delegations
+    async fn get_delegation_revocations(&self) -> Result<Vec<(String, String)>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt =
+                conn.prepare_cached("SELECT delegator, delegatee FROM delegation_revocation;")?;
+            let revocations = stmt
+                .query_map([], |r| {
+                    let delegator: Vec<u8> = r.get(0)?;
+                    let delegatee: Vec<u8> = r.get(1)?;
+                    Ok((hex::encode(delegator), hex::encode(delegatee)))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let ok: Result<Vec<(String, String)>> = Ok(revocations);
+            ok
+        })
+        .await?
+    }
+
+    /// Record a vanish request and delete the author's events
+    async fn vanish(&self, pubkey: &str, created_at: u64) -> Result<u64> {
+        let pubkey_blob = hex::decode(pubkey).ok();
+        let pubkey = pubkey.to_owned();
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let delete_count: u64;
+            {
+                tx.execute(
+                    "INSERT INTO vanish_request (pubkey, created_at) VALUES (?1, ?2) ON CONFLICT(pubkey) DO UPDATE SET created_at=MAX(created_at, excluded.created_at);",
+                    params![pubkey_blob, created_at],
+                )?;
+                // the request itself is kept, to refuse older events
+                let authored = tx.execute(
+                    "DELETE FROM event WHERE author=?1 AND created_at<=?2 AND kind!=62;",
+                    params![pubkey_blob, created_at],
+                )?;
+                let gift_wraps = tx.execute(
+                    "DELETE FROM event WHERE kind=1059 AND id IN (SELECT event_id FROM tag WHERE name='p' AND value=?1);",
+                    params![pubkey],
+                )?;
+                delete_count = (authored + gift_wraps) as u64;
+            }
+            tx.commit()?;
+            let ok: Result<u64> = Ok(delete_count);
+            ok
+        })
+        .await?
+    }
+
+    /// Get all vanish requests
+    async fn get_vanish_requests(&self) -> Result<Vec<(String, u64)>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached("SELECT pubkey, created_at FROM vanish_request;")?;
+            let requests = stmt
+                .query_map([], |r| {
+                    let pubkey: Vec<u8> = r.get(0)?;
+                    Ok((hex::encode(pubkey), r.get(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let ok: Result<Vec<(String, u64)>> = Ok(requests);
+            ok
+        })
+        .await?
+    }
+
+    /// Add an item to a relay management list
+    async fn add_policy_entry(
+        &self,
+        list: PolicyList,
+        item: &str,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let item = item.to_owned();
+        let reason = reason.map(ToOwned::to_owned);
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT INTO relay_policy (list, item, reason, created_at) VALUES (?1, ?2, ?3, strftime('%s','now')) ON CONFLICT (list, item) DO UPDATE SET reason=excluded.reason;",
+                params![list.name(), item, reason],
+            )?;
+            let ok: Result<()> = Ok(());
+            ok
+        })
+        .await?
+    }
+
+    /// Remove an item from a relay management list
+    async fn remove_policy_entry(&self, list: PolicyList, item: &str) -> Result<()> {
+        let item = item.to_owned();
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM relay_policy WHERE list=?1 AND item=?2;",
+                params![list.name(), item],
+            )?;
+            let ok: Result<()> = Ok(());
+            ok
+        })
+        .await?
+    }
+
+    /// Get all relay management list entries
+    async fn get_policy_entries(&self) -> Result<Vec<PolicyEntry>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached("SELECT list, item, reason FROM relay_policy;")?;
+            let rows = stmt
+                .query_map([], |r| {
+                    let list: String = r.get(0)?;
+                    Ok((list, r.get(1)?, r.get(2)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(String, String, Option<String>)>>>()?;
+            // ignore lists this version does not know about
+            let entries = rows
+                .into_iter()
+                .filter_map(|(list, item, reason)| {
+                    PolicyList::from_name(&list).map(|list| PolicyEntry { list, item, reason })
+                })
+                .collect();
+            let ok: Result<Vec<PolicyEntry>> = Ok(entries);
+            ok
+        })
+        .await?
+    }
+
+    /// Store a relay setting changed through the management API
+    async fn set_relay_setting(&self, name: &str, value: &str) -> Result<()> {
+        let name = name.to_owned();
+        let value = value.to_owned();
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT INTO relay_setting (name, value) VALUES (?1, ?2) ON CONFLICT (name) DO UPDATE SET value=excluded.value;",
+                params![name, value],
+            )?;
+            let ok: Result<()> = Ok(());
+            ok
+        })
+        .await?
+    }
+
+    /// Get all relay settings changed through the management API
+    async fn get_relay_settings(&self) -> Result<Vec<(String, String)>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached("SELECT name, value FROM relay_setting;")?;
+            let settings = stmt
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let ok: Result<Vec<(String, String)>> = Ok(settings);
+            ok
+        })
+        .await?
+    }
+
+    /// Hide an event
+    async fn hide_event(&self, id: &str) -> Result<u64> {
+        let id_blob = hex::decode(id).ok();
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            let hidden_count = conn.execute(
+                "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE hidden!=TRUE AND event_hash=?;",
+                params![id_blob],
+            )? as u64;
+            let ok: Result<u64> = Ok(hidden_count);
+            ok
+        })
+        .await?
+    }
+
+    /// Hide all events by an author, or under their delegation
+    async fn hide_author_events(&self, pubkey: &str) -> Result<u64> {
+        let pubkey_blob = hex::decode(pubkey).ok();
+        let conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || {
+            let hidden_count = conn.execute(
+                "UPDATE event SET hidden=TRUE, hidden_at=strftime('%s','now') WHERE hidden!=TRUE AND (author=?1 OR delegated_by=?1);",
+                params![pubkey_blob],
+            )? as u64;
+            let ok: Result<u64> = Ok(hidden_count);
+            ok
+        })
+        .await?
+    }
+
     /// Create a new verification record connected to a specific event
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
         let e = hex::decode(event_id).ok();
@@ -961,6 +1417,20 @@ fn override_index(f: &ReqFilter) -> Option<String> {
     None
 }
 
+/// Create a query counting the distinct events that match any of the
+/// filters, so events matching several filters are only counted once.
+fn count_from_filters(filters: &[ReqFilter]) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut queries = vec![];
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    for filter in filters {
+        let (q, mut p, _idx) = query_from_filter(filter);
+        queries.push(format!("SELECT event_hash FROM ({q})"));
+        params.append(&mut p);
+    }
+    let query = format!("SELECT COUNT(*) FROM ({})", queries.join(" UNION "));
+    (query, params)
+}
+
 /// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
 fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
     // build a dynamic SQL query.  all user-input is either an integer
@@ -970,7 +1440,8 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
 
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
-        let empty_query = "SELECT e.content FROM event e WHERE 1=0".to_owned();
+        let empty_query =
+            "SELECT e.content, e.created_at, e.event_hash FROM event e WHERE 1=0".to_owned();
         // query parameters for SQLite
         let empty_params: Vec<Box<dyn ToSql>> = vec![];
         return (empty_query, empty_params, None);
@@ -978,10 +1449,34 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
 
     // check if the index needs to be overridden
     let idx_name = override_index(f);
-    let idx_stmt = idx_name
-        .as_ref()
-        .map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
-    let mut query = format!("SELECT e.content FROM event e {idx_stmt}");
+    let (mut query, mut params) = filter_query(f, "author", idx_name.as_deref());
+    // events published under a NIP-26 delegation also match their
+    // delegator.  A separate branch lets each side use its own index.
+    if f.authors.is_some() {
+        let (delegated_query, mut delegated_params) =
+            filter_query(f, "delegated_by", Some("delegated_by_index"));
+        query = format!(
+            "SELECT content, created_at, event_hash FROM (SELECT * FROM ({query}) UNION SELECT * FROM ({delegated_query}))"
+        );
+        params.append(&mut delegated_params);
+        if let Some(lim) = f.limit {
+            let _ = write!(query, " ORDER BY created_at DESC LIMIT {lim}");
+        } else {
+            query.push_str(" ORDER BY created_at ASC");
+        }
+    }
+    (query, params, idx_name)
+}
+
+/// Create the SQL query and params for a single filter, matching
+/// "authors" against the given column.
+fn filter_query(
+    f: &ReqFilter,
+    author_col: &str,
+    idx_name: Option<&str>,
+) -> (String, Vec<Box<dyn ToSql>>) {
+    let idx_stmt = idx_name.map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
+    let mut query = format!("SELECT e.content, e.created_at, e.event_hash FROM event e {idx_stmt}");
     // query parameters for SQLite
     let mut params: Vec<Box<dyn ToSql>> = vec![];
 
@@ -992,7 +1487,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         // take each author and convert to a hexsearch
         let mut auth_searches: Vec<String> = vec![];
         for auth in authvec {
-            auth_searches.push("author=?".to_owned());
+            auth_searches.push(format!("{author_col}=?"));
             let auth_bin = hex::decode(auth).ok();
             params.push(Box::new(auth_bin));
         }
@@ -1070,6 +1565,13 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
             filter_components.push(tag_clause);
         }
     }
+    // Query for full-text search
+    if let Some(search) = f.search.as_ref().and_then(|s| fts_query(s)) {
+        filter_components.push(
+            "e.id IN (SELECT rowid FROM event_fts WHERE event_fts MATCH ?)".to_owned(),
+        );
+        params.push(Box::new(search));
+    }
     // Query for timestamp
     if f.since.is_some() {
         let created_clause = format!("created_at >= {}", f.since.unwrap());
@@ -1097,7 +1599,22 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     } else {
         query.push_str(" ORDER BY e.created_at ASC");
     }
-    (query, params, idx_name)
+    (query, params)
+}
+
+/// Convert a NIP-50 search string into an FTS5 query.  Each word is
+/// quoted, so that FTS5 operators in user input are matched
+/// literally, and all words must be present.
+fn fts_query(search: &str) -> Option<String> {
+    let terms: Vec<String> = search_terms(search)
+        .iter()
+        .map(|t| format!("\"{t}\""))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
 }
 
 /// Create a dynamic SQL query string and params from a subscription.
@@ -1232,6 +1749,166 @@ pub fn delete_expired(conn: &mut PooledConnection) -> Result<usize> {
     Ok(update_count)
 }
 
+/// Purge events that have been hidden for the grace period on a
+/// regular basis
+async fn cleanup_hidden(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    grace: Duration,
+    metrics: NostrMetrics,
+) -> Result<()> {
+    info!(
+        "enabling purge of hidden events (grace period: {:?})",
+        grace
+    );
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    if let Ok(mut conn) = pool.get() {
+                        // block event writes while purging
+                        let _write_guard = write_in_progress.lock().await;
+                        let start = Instant::now();
+                        let before = unix_time().saturating_sub(grace.as_secs());
+                        let purge_res = tokio::task::spawn_blocking(move || {
+                            delete_hidden(&mut conn, before)
+                        }).await;
+                        match purge_res {
+                            Ok(Ok(count)) => {
+                                if count > 0 {
+                                    metrics.purged_events.inc_by(count as u64);
+                                    info!("purged {} hidden events in: {:?}", count, start.elapsed());
+                                }
+                            },
+                            _ => {
+                                // either the task or underlying query failed
+                                info!("there was an error purging hidden events: {:?}", purge_res);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Execute a query to delete all events hidden before a timestamp.
+/// Their tags are removed by cascade.
+pub fn delete_hidden(conn: &mut PooledConnection, before: u64) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let delete_count = tx.execute(
+        "DELETE FROM event WHERE hidden=TRUE AND hidden_at <= ?",
+        params![before],
+    )?;
+    tx.commit()?;
+    Ok(delete_count)
+}
+
+/// Prune events according to the retention policy on a regular basis
+async fn cleanup_retention(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    retention: Retention,
+) -> Result<()> {
+    info!(
+        "enabling retention policy (max_events: {:?}, max_bytes: {:?}, persist_days: {:?})",
+        retention.max_events, retention.max_bytes, retention.persist_days
+    );
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    if let Ok(mut conn) = pool.get() {
+                        // block event writes while pruning
+                        let _write_guard = write_in_progress.lock().await;
+                        let start = Instant::now();
+                        let retention = retention.clone();
+                        let ret_res = tokio::task::spawn_blocking(move || {
+                            delete_for_retention(&mut conn, &retention)
+                        }).await;
+                        match ret_res {
+                            Ok(Ok(count)) => {
+                                if count > 0 {
+                                    info!("removed {} events due to retention policy in: {:?}", count, start.elapsed());
+                                }
+                            },
+                            _ => {
+                                // either the task or underlying query failed
+                                info!("there was an error applying the retention policy: {:?}", ret_res);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Execute queries to delete the oldest events that exceed the
+/// retention policy.  Events from whitelisted authors are never
+/// deleted.
+pub fn delete_for_retention(conn: &mut PooledConnection, retention: &Retention) -> Result<usize> {
+    let whitelist = retention.whitelist_blobs();
+    // restrict any deletion to non-whitelisted authors
+    let author_clause = if whitelist.is_empty() {
+        String::new()
+    } else {
+        format!("AND author NOT IN ({})", repeat_vars(whitelist.len()))
+    };
+    let whitelist_params = || -> Vec<Box<dyn ToSql>> {
+        whitelist
+            .iter()
+            .map(|w| Box::new(w.clone()) as Box<dyn ToSql>)
+            .collect()
+    };
+    let tx = conn.transaction()?;
+    let mut delete_count = 0;
+    // remove events older than the persistence window
+    if let Some(days) = retention.persist_days.filter(|d| *d > 0) {
+        let cutoff = unix_time().saturating_sub(days as u64 * 86400);
+        let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(cutoff)];
+        params.append(&mut whitelist_params());
+        let query = format!("DELETE FROM event WHERE created_at < ? {author_clause}");
+        delete_count += tx.execute(&query, rusqlite::params_from_iter(params))?;
+    }
+    // remove the oldest events beyond the maximum count
+    if let Some(max_events) = retention.max_events.filter(|m| *m > 0) {
+        let count: usize = tx.query_row("SELECT COUNT(*) FROM event", [], |r| r.get(0))?;
+        if count > max_events {
+            let mut params = whitelist_params();
+            params.push(Box::new(count - max_events));
+            let query = format!(
+                "DELETE FROM event WHERE id IN (SELECT id FROM event WHERE 1=1 {author_clause} ORDER BY created_at ASC, id ASC LIMIT ?)"
+            );
+            delete_count += tx.execute(&query, rusqlite::params_from_iter(params))?;
+        }
+    }
+    // remove the oldest events until content fits within the byte limit
+    if let Some(max_bytes) = retention.max_bytes.filter(|m| *m > 0) {
+        let total: usize = tx.query_row(
+            "SELECT COALESCE(SUM(length(CAST(content AS BLOB))), 0) FROM event",
+            [],
+            |r| r.get(0),
+        )?;
+        if total > max_bytes {
+            let mut params = whitelist_params();
+            params.push(Box::new(total - max_bytes));
+            // running total of event sizes, oldest first; delete rows
+            // until the excess has been covered.
+            let query = format!(
+                "DELETE FROM event WHERE id IN (SELECT id FROM (SELECT id, length(CAST(content AS BLOB)) AS size, SUM(length(CAST(content AS BLOB))) OVER (ORDER BY created_at ASC, id ASC) AS running FROM event WHERE 1=1 {author_clause}) WHERE running - size < ?)"
+            );
+            delete_count += tx.execute(&query, rusqlite::params_from_iter(params))?;
+        }
+    }
+    tx.commit()?;
+    Ok(delete_count)
+}
+
 /// Perform database WAL checkpoint on a regular basis
 pub async fn db_checkpoint_task(
     pool: SqlitePool,
@@ -1353,3 +2030,232 @@ fn _pool_at_capacity(pool: &SqlitePool) -> bool {
     let state: r2d2::State = pool.state();
     state.idle_connections == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A private in-memory database with the current schema.
+    fn test_conn() -> PooledConnection {
+        let manager = SqliteConnectionManager::memory().with_init(|c| c.execute_batch(STARTUP_SQL));
+        let pool: SqlitePool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        conn
+    }
+
+    /// Hex pubkey for a numbered test author.
+    fn author(n: u64) -> String {
+        format!("{n:064x}")
+    }
+
+    /// Store an (unsigned) event, returning its id.
+    fn store(
+        conn: &mut PooledConnection,
+        id: u64,
+        author_n: u64,
+        created_at: u64,
+        content: &str,
+    ) -> String {
+        let event = Event {
+            id: format!("{id:064x}"),
+            pubkey: author(author_n),
+            delegated_by: None,
+            created_at,
+            kind: 1,
+            tags: vec![],
+            content: content.to_owned(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        };
+        SqliteRepo::persist_event(conn, &event, &Settings::default().options).unwrap();
+        event.id
+    }
+
+    /// Ids of all stored events, oldest first.
+    fn stored_ids(conn: &PooledConnection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT event_hash FROM event ORDER BY created_at ASC")
+            .unwrap();
+        let ids = stmt
+            .query_map([], |r| r.get::<usize, Vec<u8>>(0))
+            .unwrap()
+            .map(|h| hex::encode(h.unwrap()))
+            .collect();
+        ids
+    }
+
+    /// A retention policy protecting author 2.
+    fn retention() -> Retention {
+        let mut retention = Settings::default().retention;
+        retention.whitelist_addresses = Some(vec![author(2)]);
+        retention
+    }
+
+    #[test]
+    fn retention_removes_old_events() {
+        let mut conn = test_conn();
+        let now = unix_time();
+        store(&mut conn, 1, 1, now - 3 * 86400, "old");
+        let protected = store(&mut conn, 2, 2, now - 3 * 86400, "old");
+        let recent = store(&mut conn, 3, 1, now - 60, "new");
+        let mut retention = retention();
+        retention.persist_days = Some(2);
+        assert_eq!(delete_for_retention(&mut conn, &retention).unwrap(), 1);
+        assert_eq!(stored_ids(&conn), vec![protected, recent]);
+    }
+
+    #[test]
+    fn retention_limits_event_count() {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let protected = store(&mut conn, 1, 2, now - 40, "a");
+        store(&mut conn, 2, 1, now - 30, "b");
+        store(&mut conn, 3, 1, now - 20, "c");
+        let newest = store(&mut conn, 4, 1, now - 10, "d");
+        let mut retention = retention();
+        retention.max_events = Some(2);
+        assert_eq!(delete_for_retention(&mut conn, &retention).unwrap(), 2);
+        assert_eq!(stored_ids(&conn), vec![protected, newest]);
+    }
+
+    #[test]
+    fn retention_limits_content_size() {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let protected = store(&mut conn, 1, 2, now - 40, "aaaa");
+        store(&mut conn, 2, 1, now - 30, "bbbb");
+        store(&mut conn, 3, 1, now - 20, "cccc");
+        let newest = store(&mut conn, 4, 1, now - 10, "dddd");
+        // every stored event has the same size
+        let size: usize = conn
+            .query_row(
+                "SELECT length(CAST(content AS BLOB)) FROM event LIMIT 1",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        let mut retention = retention();
+        retention.max_bytes = Some(2 * size);
+        assert_eq!(delete_for_retention(&mut conn, &retention).unwrap(), 2);
+        assert_eq!(stored_ids(&conn), vec![protected, newest]);
+    }
+
+    #[test]
+    fn count_overlapping_filters_once() {
+        let mut conn = test_conn();
+        let now = unix_time();
+        store(&mut conn, 1, 1, now - 30, "a");
+        store(&mut conn, 2, 1, now - 20, "b");
+        store(&mut conn, 3, 2, now - 10, "c");
+        // the first two events match both filters
+        let sub: Subscription = serde_json::from_str(&format!(
+            r#"["REQ","c",{{"authors":["{}"]}},{{"kinds":[1],"until":{}}}]"#,
+            author(1),
+            now - 15
+        ))
+        .unwrap();
+        let (q, p) = count_from_filters(&sub.filters);
+        let count: u64 = conn
+            .query_row(&q, rusqlite::params_from_iter(p), |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn search_matches_whole_words() {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let pallet = store(&mut conn, 1, 1, now - 20, "Pallet received, LOT-2024-0042");
+        store(&mut conn, 2, 1, now - 10, "Pallets moved to slot-2024");
+        let sub: Subscription =
+            serde_json::from_str(r#"["REQ","s",{"search":"lot-2024 PALLET"}]"#).unwrap();
+        let (q, p, _) = query_from_filter(&sub.filters[0]);
+        let mut stmt = conn.prepare(&q).unwrap();
+        let found: Vec<String> = stmt
+            .query_map(rusqlite::params_from_iter(p), |r| r.get::<usize, String>(0))
+            .unwrap()
+            .map(|e| serde_json::from_str::<Event>(&e.unwrap()).unwrap().id)
+            .collect();
+        assert_eq!(found, vec![pallet]);
+    }
+
+    #[test]
+    fn hidden_events_removed_after_grace_period() {
+        let mut conn = test_conn();
+        let now = unix_time();
+        for id in [1, 2] {
+            let event = Event {
+                id: format!("{id:064x}"),
+                pubkey: author(1),
+                delegated_by: None,
+                created_at: now - 100,
+                kind: 1,
+                tags: vec![vec!["t".to_owned(), "spam".to_owned()]],
+                content: "buy now".to_owned(),
+                sig: "0".to_owned(),
+                tagidx: None,
+            };
+            SqliteRepo::persist_event(&mut conn, &event, &Settings::default().options).unwrap();
+        }
+        // the first was hidden long ago, the second only just now
+        conn.execute_batch(&format!(
+            "UPDATE event SET hidden=TRUE, hidden_at={} WHERE id=1;
+             UPDATE event SET hidden=TRUE, hidden_at={} WHERE id=2;",
+            now - 90,
+            now - 5
+        ))
+        .unwrap();
+        assert_eq!(delete_hidden(&mut conn, now - 60).unwrap(), 1);
+        assert_eq!(stored_ids(&conn), vec![format!("{:064x}", 2)]);
+        let count = |sql: &str| -> u64 { conn.query_row(sql, [], |r| r.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM tag WHERE event_id=1"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM event_fts WHERE rowid=1"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM tag WHERE event_id=2"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM event_fts WHERE rowid=2"), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_sent_closed() {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = crate::db::build_repo(&settings, metrics).await;
+        let now = unix_time();
+        for id in 1..=5 {
+            let event = Event {
+                id: format!("{:064x}", 0x5100 + id),
+                pubkey: author(0x51),
+                delegated_by: None,
+                created_at: now - id,
+                kind: 1,
+                tags: vec![],
+                content: "hello".to_owned(),
+                sig: "0".to_owned(),
+                tagidx: None,
+            };
+            repo.write_event(&event).await.unwrap();
+        }
+        let sub: Subscription = serde_json::from_str(&format!(
+            r#"["REQ","slow",{{"authors":["{}"]}}]"#,
+            author(0x51)
+        ))
+        .unwrap();
+        // the client never reads, so the channel fills after one event
+        let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(2);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, 1, "cid".to_owned(), query_tx, abandon_rx, None)
+            .await
+            .unwrap();
+        // give up on the client, which happens after two seconds
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let first = query_rx.recv().await.unwrap();
+        assert_eq!(first.closed, None);
+        let last = query_rx.recv().await.unwrap();
+        assert_eq!(
+            last.closed.as_deref(),
+            Some("rate-limited: client is not reading results fast enough")
+        );
+        assert!(query_rx.recv().await.is_none());
+    }
+}
@@ -23,7 +23,7 @@ pragma mmap_size = 0; -- disable mmap (default)
 "##;
 
 /// Latest database version
-pub const DB_VERSION: usize = 18;
+pub const DB_VERSION: usize = 23;
 
 /// Schema definition
 const INIT_SQL: &str = formatcp!(
@@ -48,6 +48,7 @@ author BLOB NOT NULL, -- author pubkey
 delegated_by BLOB, -- delegator pubkey (NIP-26)
 kind INTEGER NOT NULL, -- event kind
 hidden INTEGER, -- relevant for queries
+hidden_at INTEGER, -- when the event was hidden (seconds since 1970)
 content TEXT NOT NULL -- serialized json of event object
 );
 
@@ -63,6 +64,7 @@ CREATE INDEX IF NOT EXISTS kind_created_at_index ON event(kind,created_at);
 CREATE INDEX IF NOT EXISTS author_created_at_index ON event(author,created_at);
 CREATE INDEX IF NOT EXISTS author_kind_index ON event(author,kind);
 CREATE INDEX IF NOT EXISTS event_expiration ON event(expires_at);
+CREATE INDEX IF NOT EXISTS event_hidden_at ON event(hidden_at) WHERE hidden_at IS NOT NULL;
 
 -- Tag Table
 -- Tag values are stored as either a BLOB (if they come in as a
@@ -124,6 +126,46 @@ CONSTRAINT invoice_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey)
 -- Create invoice index
 CREATE INDEX IF NOT EXISTS invoice_pubkey_index ON invoice(pubkey);
 
+-- Full-text search (NIP-50)
+-- Indexes the content field of each event, keyed by event rowid.
+-- Triggers keep the index in sync with the event table.
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_insert AFTER INSERT ON event BEGIN
+INSERT INTO event_fts(rowid, content) VALUES (new.id, json_extract(new.content, '$.content'));
+END;
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event BEGIN
+DELETE FROM event_fts WHERE rowid=old.id;
+END;
+
+-- Revoked delegations (NIP-26)
+CREATE TABLE IF NOT EXISTS delegation_revocation (
+delegator BLOB NOT NULL, -- delegator pubkey
+delegatee BLOB NOT NULL, -- revoked delegatee pubkey
+revoked_at INTEGER NOT NULL, -- when the revocation was recorded
+PRIMARY KEY (delegator, delegatee)
+);
+
+-- Requests to vanish (NIP-62)
+CREATE TABLE IF NOT EXISTS vanish_request (
+pubkey BLOB PRIMARY KEY, -- pubkey that requested to vanish
+created_at INTEGER NOT NULL -- events created at or before this time are refused
+);
+
+-- Relay management lists (NIP-86)
+CREATE TABLE IF NOT EXISTS relay_policy (
+id INTEGER PRIMARY KEY,
+list TEXT NOT NULL, -- list name, such as banned_pubkey or allowed_kind
+item TEXT NOT NULL, -- pubkey, event id, kind, or IP address
+reason TEXT, -- explanation given by the admin
+created_at INTEGER NOT NULL -- when the entry was added
+);
+CREATE UNIQUE INDEX IF NOT EXISTS relay_policy_list_item_index ON relay_policy(list, item);
+
+-- Relay settings changed through the management API (NIP-86)
+CREATE TABLE IF NOT EXISTS relay_setting (
+name TEXT PRIMARY KEY, -- setting name
+value TEXT NOT NULL -- setting value
+);
 
 "##,
     DB_VERSION
@@ -245,6 +287,21 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
             if curr_version == 17 {
                 curr_version = mig_17_to_18(conn)?;
             }
+            if curr_version == 18 {
+                curr_version = mig_18_to_19(conn)?;
+            }
+            if curr_version == 19 {
+                curr_version = mig_19_to_20(conn)?;
+            }
+            if curr_version == 20 {
+                curr_version = mig_20_to_21(conn)?;
+            }
+            if curr_version == 21 {
+                curr_version = mig_21_to_22(conn)?;
+            }
+            if curr_version == 22 {
+                curr_version = mig_22_to_23(conn)?;
+            }
 
             if curr_version == DB_VERSION {
                 info!(
@@ -839,3 +896,136 @@ PRAGMA user_version = 18;
     }
     Ok(18)
 }
+
+fn mig_18_to_19(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 18->19");
+    let start = Instant::now();
+    let upgrade_sql = r##"
+-- Full-text search (NIP-50)
+-- Indexes the content field of each event, keyed by event rowid.
+-- Triggers keep the index in sync with the event table.
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_insert AFTER INSERT ON event BEGIN
+INSERT INTO event_fts(rowid, content) VALUES (new.id, json_extract(new.content, '$.content'));
+END;
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event BEGIN
+DELETE FROM event_fts WHERE rowid=old.id;
+END;
+-- Index existing events
+INSERT INTO event_fts(rowid, content) SELECT id, json_extract(content, '$.content') FROM event;
+PRAGMA user_version = 19;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!(
+                "database schema upgraded v18 -> v19 in {:?}",
+                start.elapsed()
+            );
+        }
+        Err(err) => {
+            error!("update (v18->v19) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(19)
+}
+
+fn mig_19_to_20(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 19->20");
+    let upgrade_sql = r##"
+-- Revoked delegations (NIP-26)
+CREATE TABLE IF NOT EXISTS delegation_revocation (
+delegator BLOB NOT NULL, -- delegator pubkey
+delegatee BLOB NOT NULL, -- revoked delegatee pubkey
+revoked_at INTEGER NOT NULL, -- when the revocation was recorded
+PRIMARY KEY (delegator, delegatee)
+);
+PRAGMA user_version = 20;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v19 -> v20");
+        }
+        Err(err) => {
+            error!("update (v19->v20) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(20)
+}
+
+fn mig_20_to_21(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 20->21");
+    // events hidden before this version are treated as hidden now,
+    // so they receive the full purge grace period.
+    let upgrade_sql = r##"
+ALTER TABLE event ADD hidden_at INTEGER;
+UPDATE event SET hidden_at=strftime('%s','now') WHERE hidden=TRUE;
+CREATE INDEX IF NOT EXISTS event_hidden_at ON event(hidden_at) WHERE hidden_at IS NOT NULL;
+PRAGMA user_version = 21;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v20 -> v21");
+        }
+        Err(err) => {
+            error!("update (v20->v21) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(21)
+}
+
+fn mig_21_to_22(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 21->22");
+    let upgrade_sql = r##"
+-- Requests to vanish (NIP-62)
+CREATE TABLE IF NOT EXISTS vanish_request (
+pubkey BLOB PRIMARY KEY, -- pubkey that requested to vanish
+created_at INTEGER NOT NULL -- events created at or before this time are refused
+);
+PRAGMA user_version = 22;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v21 -> v22");
+        }
+        Err(err) => {
+            error!("update (v21->v22) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(22)
+}
+
+fn mig_22_to_23(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 22->23");
+    let upgrade_sql = r##"
+-- Relay management lists (NIP-86)
+CREATE TABLE IF NOT EXISTS relay_policy (
+id INTEGER PRIMARY KEY,
+list TEXT NOT NULL, -- list name, such as banned_pubkey or allowed_kind
+item TEXT NOT NULL, -- pubkey, event id, kind, or IP address
+reason TEXT, -- explanation given by the admin
+created_at INTEGER NOT NULL -- when the entry was added
+);
+CREATE UNIQUE INDEX IF NOT EXISTS relay_policy_list_item_index ON relay_policy(list, item);
+
+-- Relay settings changed through the management API (NIP-86)
+CREATE TABLE IF NOT EXISTS relay_setting (
+name TEXT PRIMARY KEY, -- setting name
+value TEXT NOT NULL -- setting value
+);
+PRAGMA user_version = 23;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v22 -> v23");
+        }
+        Err(err) => {
+            error!("update (v22->v23) failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(23)
+}
@@ -3,6 +3,7 @@ use crate::close::Close;
 use crate::close::CloseCmd;
 use crate::config::{Settings, VerifiedUsersMode};
 use crate::conn;
+use crate::control::{ClientRegistry, ClientStats, ControlMessage, RelayStats};
 use crate::db;
 use crate::db::SubmittedEvent;
 use crate::error::{Error, Result};
@@ -10,17 +11,26 @@ use crate::event::Event;
 use crate::event::EventCmd;
 use crate::event::EventWrapper;
 use crate::info::RelayInfo;
+use crate::listener::{ListenerRole, UnixIncoming};
+use crate::management;
+use crate::management::SharedPolicy;
+use crate::negentropy;
+use crate::negentropy::{NegClose, NegMsg, NegOpen};
 use crate::nip05;
-use crate::notice::Notice;
+use crate::notice::{EventResultStatus, Notice};
 use crate::payment;
 use crate::payment::InvoiceInfo;
 use crate::payment::PaymentMessage;
 use crate::repo::NostrRepo;
 use crate::server::Error::CommandUnknownError;
 use crate::server::EventWrapper::{WrappedAuth, WrappedEvent};
-use crate::subscription::Subscription;
+use crate::subscription::{Count, Subscription};
+use crate::tls::{TlsConn, TlsIncoming};
+use crate::utils::{is_lower_hex, unix_time};
 use futures::SinkExt;
 use futures::StreamExt;
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
 use governor::{Jitter, Quota, RateLimiter};
 use http::header::HeaderMap;
 use hyper::body::to_bytes;
@@ -42,19 +52,24 @@ use serde_json::json;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fs::File;
+use std::future::Future;
 use std::io::BufReader;
 use std::io::Read;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::Receiver as MpscReceiver;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
+use tokio::net::UnixStream;
 use tokio::runtime::Builder;
 use tokio::sync::broadcast::{self, Receiver, Sender};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio_tungstenite::WebSocketStream;
 use tracing::{debug, error, info, trace, warn};
 use tungstenite::error::CapacityError::MessageTooLong;
@@ -68,8 +83,9 @@ use tungstenite::protocol::WebSocketConfig;
 async fn handle_web_request(
     mut request: Request<Body>,
     repo: Arc<dyn NostrRepo>,
-    settings: Settings,
-    remote_addr: SocketAddr,
+    settings_rx: watch::Receiver<Settings>,
+    remote_addr: Option<SocketAddr>,
+    role: ListenerRole,
     broadcast: Sender<Event>,
     event_tx: tokio::sync::mpsc::Sender<SubmittedEvent>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
@@ -77,7 +93,20 @@ async fn handle_web_request(
     favicon: Option<Vec<u8>>,
     registry: Registry,
     metrics: NostrMetrics,
+    policy: SharedPolicy,
+    clients: ClientRegistry,
 ) -> Result<Response<Body>, Infallible> {
+    let settings = settings_rx.borrow().clone();
+    // keep admin endpoints off the relay listeners, if they have
+    // their own listener
+    let admin_request =
+        request.uri().path() == "/metrics" || management::is_management_request(&request);
+    if !role.serves(admin_request) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(""))
+            .unwrap());
+    }
     match (
         request.uri().path(),
         request.headers().contains_key(header::UPGRADE),
@@ -85,6 +114,28 @@ async fn handle_web_request(
         // Request for / as websocket
         ("/", true) => {
             trace!("websocket with upgrade request");
+            // determine the remote IP from headers if the exist
+            let header_ip = settings
+                .network
+                .remote_ip_header
+                .as_ref()
+                .and_then(|x| get_header_string(x, request.headers()));
+            // use the socket addr as a backup (unix sockets have none)
+            let Some(remote_ip) = header_ip.or_else(|| remote_addr.map(|a| a.ip().to_string()))
+            else {
+                warn!("refusing connection without a client address header");
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Client address is unknown"))
+                    .unwrap());
+            };
+            if policy.read().unwrap().is_ip_blocked(&remote_ip) {
+                info!("refusing connection from blocked ip: {:?}", remote_ip);
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("IP address is blocked"))
+                    .unwrap());
+            }
             //assume request is a handshake, so create the handshake response
             let response = match handshake::server::create_response_with_body(&request, || {
                 Body::empty()
@@ -115,15 +166,6 @@ async fn handle_web_request(
                                 .await;
                                 let origin = get_header_string("origin", request.headers());
                                 let user_agent = get_header_string("user-agent", request.headers());
-                                // determine the remote IP from headers if the exist
-                                let header_ip = settings
-                                    .network
-                                    .remote_ip_header
-                                    .as_ref()
-                                    .and_then(|x| get_header_string(x, request.headers()));
-                                // use the socket addr as a backup
-                                let remote_ip =
-                                    header_ip.unwrap_or_else(|| remote_addr.ip().to_string());
                                 let client_info = ClientInfo {
                                     remote_ip,
                                     user_agent,
@@ -133,18 +175,19 @@ async fn handle_web_request(
                                 tokio::spawn(nostr_server(
                                     repo,
                                     client_info,
-                                    settings,
+                                    settings_rx,
                                     ws_stream,
                                     broadcast,
                                     event_tx,
                                     shutdown,
                                     metrics,
+                                    clients,
                                 ));
                             }
                             // todo: trace, don't print...
                             Err(e) => println!(
                                 "error when trying to upgrade connection \
-                                 from address {remote_addr} to websocket connection. \
+                                 from address {remote_ip} to websocket connection. \
                                  Error is: {e}",
                             ),
                         }
@@ -164,6 +207,10 @@ async fn handle_web_request(
         }
         // Request for Relay info
         ("/", false) => {
+            // relay management requests (NIP-86)
+            if management::is_management_request(&request) {
+                return Ok(management::handle_request(request, repo, policy, &settings).await);
+            }
             // handle request at root with no upgrade header
             // Check if this is a nostr server info request
             let accept_header = &request.headers().get(ACCEPT);
@@ -173,7 +220,8 @@ async fn handle_web_request(
                     if mt_str.contains("application/nostr+json") {
                         // build a relay info response
                         debug!("Responding to server info request");
-                        let rinfo = RelayInfo::from(settings);
+                        let mut rinfo = RelayInfo::from(settings);
+                        policy.read().unwrap().update_relay_info(&mut rinfo);
                         let b = Body::from(serde_json::to_string_pretty(&rinfo).unwrap());
                         return Ok(Response::builder()
                             .status(200)
@@ -673,7 +721,7 @@ async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     }
 }
 
-fn create_metrics() -> (Registry, NostrMetrics) {
+pub(crate) fn create_metrics() -> (Registry, NostrMetrics) {
     // setup prometheus registry
     let registry = Registry::new();
 
@@ -716,11 +764,20 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         IntCounter::with_opts(Opts::new("nostr_cmd_close_total", "CLOSE commands")).unwrap();
     let cmd_auth =
         IntCounter::with_opts(Opts::new("nostr_cmd_auth_total", "AUTH commands")).unwrap();
+    let cmd_count =
+        IntCounter::with_opts(Opts::new("nostr_cmd_count_total", "COUNT commands")).unwrap();
+    let cmd_neg =
+        IntCounter::with_opts(Opts::new("nostr_cmd_neg_total", "NEG-OPEN commands")).unwrap();
     let disconnects = IntCounterVec::new(
         Opts::new("nostr_disconnects_total", "Client disconnects"),
         vec!["reason"].as_slice(),
     )
     .unwrap();
+    let purged_events = IntCounter::with_opts(Opts::new(
+        "nostr_events_purged_total",
+        "Hidden events purged",
+    ))
+    .unwrap();
     registry.register(Box::new(query_sub.clone())).unwrap();
     registry.register(Box::new(query_db.clone())).unwrap();
     registry.register(Box::new(write_events.clone())).unwrap();
@@ -732,7 +789,10 @@ fn create_metrics() -> (Registry, NostrMetrics) {
     registry.register(Box::new(cmd_event.clone())).unwrap();
     registry.register(Box::new(cmd_close.clone())).unwrap();
     registry.register(Box::new(cmd_auth.clone())).unwrap();
+    registry.register(Box::new(cmd_count.clone())).unwrap();
+    registry.register(Box::new(cmd_neg.clone())).unwrap();
     registry.register(Box::new(disconnects.clone())).unwrap();
+    registry.register(Box::new(purged_events.clone())).unwrap();
     let metrics = NostrMetrics {
         query_sub,
         query_db,
@@ -746,6 +806,9 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         cmd_event,
         cmd_close,
         cmd_auth,
+        cmd_count,
+        cmd_neg,
+        purged_events,
     };
     (registry, metrics)
 }
@@ -760,19 +823,13 @@ fn file_bytes(path: &str) -> Result<Vec<u8>> {
 }
 
 /// Start running a Nostr relay server.
-pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Result<(), Error> {
+pub fn start_server(settings: &Settings, ctrl_rx: MpscReceiver<ControlMessage>) -> Result<(), Error> {
     trace!("Config: {:?}", settings);
     // do some config validation.
     if !Path::new(&settings.database.data_directory).is_dir() {
         error!("Database directory does not exist");
         return Err(Error::DatabaseDirError);
     }
-    let addr = format!(
-        "{}:{}",
-        settings.network.address.trim(),
-        settings.network.port
-    );
-    let socket_addr = addr.parse().expect("listening address not valid");
     // address whitelisting settings
     if let Some(addr_whitelist) = &settings.authorization.pubkey_whitelist {
         info!(
@@ -825,7 +882,6 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let persist_buffer_limit = settings.limits.event_persist_buffer;
         let verified_users_active = settings.verified_users.is_active();
         let settings = settings.clone();
-        info!("listening on: {}", socket_addr);
         // all client-submitted valid events are broadcast to every
         // other client on this channel.  This should be large enough
         // to accommodate slower readers (messages are dropped if
@@ -849,20 +905,31 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
 
         let (payment_tx, payment_rx) = broadcast::channel::<PaymentMessage>(4096);
 
+        // current settings, replaced when a reload is requested.
+        // Tasks and connections watch this to apply changes.
+        let (settings_tx, settings_rx) = watch::channel(settings.clone());
+
         let (registry, metrics) = create_metrics();
 
         // build a repository for events
         let repo = db::build_repo(&settings, metrics.clone()).await;
+        // load the relay management lists and settings (NIP-86)
+        let policy: SharedPolicy = Arc::new(RwLock::new(
+            management::load_policy(repo.as_ref(), &settings)
+                .await
+                .expect("could not load relay management lists"),
+        ));
         // start the database writer task.  Give it a channel for
         // writing events, and for publishing events that have been
         // written (to all connected clients).
         tokio::task::spawn(db::db_writer(
             repo.clone(),
-            settings.clone(),
+            settings_rx.clone(),
             event_rx,
             bcast_tx.clone(),
             metadata_tx.clone(),
             payment_tx.clone(),
+            policy.clone(),
             shutdown_listen,
         ));
         info!("db writer created");
@@ -873,7 +940,7 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
                 repo.clone(),
                 metadata_rx,
                 bcast_tx.clone(),
-                settings.clone(),
+                settings_rx.clone(),
             );
             if let Ok(mut v) = verifier_opt {
                 if verified_users_active {
@@ -902,24 +969,31 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             }
         }
 
-        // listen for (external to tokio) shutdown request
-        let controlled_shutdown = invoke_shutdown.clone();
-        tokio::spawn(async move {
-            info!("control message listener started");
-            match shutdown_rx.recv() {
-                Ok(()) => {
-                    info!("control message requesting shutdown");
-                    controlled_shutdown.send(()).ok();
-                }
-                Err(std::sync::mpsc::RecvError) => {
-                    trace!("shutdown requestor is disconnected (this is normal)");
+        // connected clients, for stats and disconnecting
+        let clients = ClientRegistry::default();
+        // forward (external to tokio) control messages.  This uses a
+        // plain thread, so a blocked receive never holds up shutdown.
+        let (ctrl_tx, ctrl_async_rx) = mpsc::channel::<ControlMessage>(32);
+        thread::spawn(move || {
+            while let Ok(msg) = ctrl_rx.recv() {
+                if ctrl_tx.blocking_send(msg).is_err() {
+                    break;
                 }
-            };
+            }
         });
+        tokio::spawn(run_control_plane(
+            ctrl_async_rx,
+            repo.clone(),
+            policy.clone(),
+            settings_tx,
+            clients.clone(),
+            metrics.clone(),
+            invoke_shutdown.clone(),
+        ));
         // listen for ctrl-c interruupts
         let ctrl_c_shutdown = invoke_shutdown.clone();
         // listener for webserver shutdown
-        let webserver_shutdown_listen = invoke_shutdown.subscribe();
+        let webserver_shutdown = invoke_shutdown.clone();
 
         tokio::spawn(async move {
             tokio::signal::ctrl_c().await.unwrap();
@@ -938,25 +1012,27 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
 
         // A `Service` is needed for every connection, so this
         // creates one from our `handle_request` function.
-        let make_svc = make_service_fn(|conn: &AddrStream| {
+        let new_service = move |remote_addr: Option<SocketAddr>, role: ListenerRole| {
             let repo = repo.clone();
-            let remote_addr = conn.remote_addr();
             let bcast = bcast_tx.clone();
             let event = event_tx.clone();
             let payment_tx = payment_tx.clone();
             let stop = invoke_shutdown.clone();
-            let settings = settings.clone();
+            let settings_rx = settings_rx.clone();
             let favicon = favicon.clone();
             let registry = registry.clone();
             let metrics = metrics.clone();
+            let policy = policy.clone();
+            let clients = clients.clone();
             async move {
                 // service_fn converts our function into a `Service`
                 Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
                     handle_web_request(
                         request,
                         repo.clone(),
-                        settings.clone(),
+                        settings_rx.clone(),
                         remote_addr,
+                        role,
                         bcast.clone(),
                         event.clone(),
                         payment_tx.clone(),
@@ -964,19 +1040,141 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
                         favicon.clone(),
                         registry.clone(),
                         metrics.clone(),
+                        policy.clone(),
+                        clients.clone(),
                     )
                 }))
             }
-        });
-        let server = Server::bind(&socket_addr)
-            .serve(make_svc)
-            .with_graceful_shutdown(ctrl_c_or_signal(webserver_shutdown_listen));
+        };
+        // bind every listener before serving any of them
+        let has_admin_listener = settings.network.has_admin_listener();
+        let mut servers: Vec<Pin<Box<dyn Future<Output = hyper::Result<()>>>>> = vec![];
+        for listener in settings.network.listeners() {
+            let role = ListenerRole::new(listener.admin, has_admin_listener);
+            let new_service = new_service.clone();
+            let shutdown = ctrl_c_or_signal(webserver_shutdown.subscribe());
+            if let Some(path) = &listener.unix_socket {
+                info!("listening on: {} ({:?})", path, role);
+                let incoming = UnixIncoming::bind(Path::new(path), listener.socket_mode())?;
+                servers.push(Box::pin(
+                    Server::builder(incoming)
+                        .serve(make_service_fn(move |_: &UnixStream| {
+                            new_service(None, role)
+                        }))
+                        .with_graceful_shutdown(shutdown),
+                ));
+                continue;
+            }
+            let address = listener.address.unwrap_or_default();
+            let socket_addr: SocketAddr = address.parse().map_err(|_| {
+                Error::CustomError(format!("listening address not valid: {address}"))
+            })?;
+            match &settings.network.tls {
+                // admin listeners are meant to be private, so skip TLS
+                Some(tls) if role != ListenerRole::Admin => {
+                    info!("listening on: {} ({:?}, TLS)", socket_addr, role);
+                    let incoming = TlsIncoming::bind(socket_addr, tls).await?;
+                    servers.push(Box::pin(
+                        Server::builder(incoming)
+                            .serve(make_service_fn(move |conn: &TlsConn| {
+                                new_service(Some(conn.remote_addr()), role)
+                            }))
+                            .with_graceful_shutdown(shutdown),
+                    ));
+                }
+                _ => {
+                    info!("listening on: {} ({:?})", socket_addr, role);
+                    servers.push(Box::pin(
+                        Server::try_bind(&socket_addr)?
+                            .serve(make_service_fn(move |conn: &AddrStream| {
+                                new_service(Some(conn.remote_addr()), role)
+                            }))
+                            .with_graceful_shutdown(shutdown),
+                    ));
+                }
+            }
+        }
         // run hyper in this thread.  This is why the thread does not return.
-        if let Err(e) = server.await {
-            eprintln!("server error: {e}");
+        for served in futures::future::join_all(servers).await {
+            if let Err(e) = served {
+                eprintln!("server error: {e}");
+            }
         }
-    });
-    Ok(())
+        Ok(())
+    })
+}
+
+/// Apply control messages sent by an embedding application.
+async fn run_control_plane(
+    mut ctrl_rx: mpsc::Receiver<ControlMessage>,
+    repo: Arc<dyn NostrRepo>,
+    policy: SharedPolicy,
+    settings_tx: watch::Sender<Settings>,
+    clients: ClientRegistry,
+    metrics: NostrMetrics,
+    shutdown: Sender<()>,
+) {
+    info!("control message listener started");
+    while let Some(msg) = ctrl_rx.recv().await {
+        match msg {
+            ControlMessage::Shutdown => {
+                info!("control message requesting shutdown");
+                shutdown.send(()).ok();
+            }
+            ControlMessage::ReloadSettings(new_settings) => {
+                let (new_settings, restart_required) =
+                    settings_tx.borrow().reload_from(*new_settings);
+                for name in restart_required {
+                    warn!("setting {} was changed, but requires a restart to apply", name);
+                }
+                match management::load_policy(repo.as_ref(), &new_settings).await {
+                    Ok(p) => *policy.write().unwrap() = p,
+                    Err(e) => warn!("could not reload relay management lists: {:?}", e),
+                }
+                settings_tx.send_replace(new_settings);
+                info!("control message reloaded settings");
+            }
+            ControlMessage::BanPubkey { pubkey, reason } => {
+                if pubkey.len() != 64 || !is_lower_hex(&pubkey) {
+                    warn!("control message ban ignored, invalid pubkey: {:?}", pubkey);
+                    continue;
+                }
+                match management::ban_pubkey(repo.as_ref(), &policy, &pubkey, reason).await {
+                    Ok(hidden) => info!(
+                        "control message banned pubkey: {:?} (hid {} events)",
+                        pubkey, hidden
+                    ),
+                    Err(e) => warn!("could not ban pubkey {:?}: {:?}", pubkey, e),
+                }
+            }
+            ControlMessage::DisconnectClient(client_id) => {
+                if !clients.disconnect(&client_id) {
+                    info!("control message disconnect ignored, unknown client: {:?}", client_id);
+                }
+            }
+            ControlMessage::DumpStats(reply) => {
+                let stats = RelayStats {
+                    clients: clients.clients(),
+                    connections_total: metrics.connections.get(),
+                    events_received: metrics.cmd_event.get(),
+                    events_written: metrics.write_events.get_sample_count(),
+                    subscriptions_received: metrics.cmd_req.get(),
+                };
+                reply.send(stats).ok();
+            }
+            ControlMessage::Checkpoint => {
+                if let Err(e) = repo.checkpoint_db().await {
+                    warn!("control message checkpoint failed: {:?}", e);
+                }
+            }
+            ControlMessage::Vacuum => {
+                if let Err(e) = repo.vacuum_db().await {
+                    warn!("control message vacuum failed: {:?}", e);
+                }
+            }
+        }
+    }
+    trace!("control requestor is disconnected (this is normal)");
 }
 
 /// Nostr protocol messages from a client
@@ -987,8 +1185,16 @@ pub enum NostrMessage {
     EventMsg(EventCmd),
     /// A `REQ` message
     SubMsg(Subscription),
+    /// A `NEG-OPEN` message
+    NegOpenMsg(NegOpen),
+    /// A `NEG-MSG` message
+    NegMsg(NegMsg),
+    /// A `NEG-CLOSE` message
+    NegCloseMsg(NegClose),
     /// A `CLOSE` message
     CloseMsg(CloseCmd),
+    /// A `COUNT` message
+    CountMsg(Count),
 }
 
 /// Convert Message to `NostrMessage`
@@ -1001,6 +1207,9 @@ fn convert_to_msg(msg: &str, max_bytes: Option<usize>) -> Result<NostrMessage> {
                 // note; this only prints the first 16k of a REQ and then truncates.
                 trace!("REQ: {:?}", msg);
             };
+            if let NostrMessage::CountMsg(_) = m {
+                trace!("COUNT: {:?}", msg);
+            };
             if let NostrMessage::EventMsg(_) = m {
                 if let Some(max_size) = max_bytes {
                     // check length, ensure that some max size is set.
@@ -1025,11 +1234,23 @@ fn make_notice_message(notice: &Notice) -> Message {
         Notice::Message(ref msg) => json!(["NOTICE", msg]),
         Notice::EventResult(ref res) => json!(["OK", res.id, res.status.to_bool(), res.msg]),
         Notice::AuthChallenge(ref challenge) => json!(["AUTH", challenge]),
+        Notice::Closed(ref res) => json!(["CLOSED", res.sub_id, res.msg]),
     };
 
     Message::text(json.to_string())
 }
 
+/// Turn a hex-encoded negentropy reply into a NEG-MSG message ready
+/// to send over a `WebSocket`
+fn make_neg_message(sub_id: &str, msg: &str) -> Message {
+    Message::text(json!(["NEG-MSG", sub_id, msg]).to_string())
+}
+
+/// Turn a reason into a NEG-ERR message ready to send over a `WebSocket`
+fn make_neg_err_message(sub_id: &str, reason: &str) -> Message {
+    Message::text(json!(["NEG-ERR", sub_id, reason]).to_string())
+}
+
 fn allowed_to_send(event_str: &str, conn: &conn::ClientConn, settings: &Settings) -> bool {
     // TODO: pass in kind so that we can avoid deserialization for most events
     if settings.authorization.nip42_dms {
@@ -1053,6 +1274,36 @@ fn allowed_to_send(event_str: &str, conn: &conn::ClientConn, settings: &Settings
     }
 }
 
+/// Machine-readable reason for refusing a subscription or count.
+fn sub_error_status(e: &Error) -> EventResultStatus {
+    match e {
+        Error::SubIdMaxLengthError => EventResultStatus::Invalid,
+        Error::DbQueryMaxExceededError => EventResultStatus::RateLimited,
+        _ => EventResultStatus::Blocked,
+    }
+}
+
+/// Does this read need the client to be authenticated first?
+fn read_requires_auth(sub: &Subscription, conn: &conn::ClientConn, settings: &Settings) -> bool {
+    let auth = &settings.authorization;
+    auth.auth_required_for_reads
+        && conn.auth_pubkey().is_none()
+        && auth
+            .auth_required_kinds
+            .as_ref()
+            .is_none_or(|kinds| sub.may_match_kinds(kinds))
+}
+
+/// Rate limiter for subscription creation, if a limit is set.
+fn subscription_limiter(
+    sub_per_min: Option<u32>,
+) -> Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> {
+    let sub_per_min = sub_per_min.filter(|s| *s > 0)?;
+    trace!("Rate limits for sub creation ({}/min)", sub_per_min);
+    let quota_time = core::num::NonZeroU32::new(sub_per_min).unwrap();
+    Some(RateLimiter::direct(Quota::per_minute(quota_time)))
+}
+
 struct ClientInfo {
     remote_ip: String,
     user_agent: Option<String>,
@@ -1065,32 +1316,31 @@ struct ClientInfo {
 async fn nostr_server(
     repo: Arc<dyn NostrRepo>,
     client_info: ClientInfo,
-    settings: Settings,
+    mut settings_rx: watch::Receiver<Settings>,
     mut ws_stream: WebSocketStream<Upgraded>,
     broadcast: Sender<Event>,
     event_tx: mpsc::Sender<SubmittedEvent>,
     mut shutdown: Receiver<()>,
     metrics: NostrMetrics,
+    clients: ClientRegistry,
 ) {
+    // settings are re-read when a reload is requested
+    let mut settings = settings_rx.borrow_and_update().clone();
     // the time this websocket nostr server started
     let orig_start = Instant::now();
     // get a broadcast channel for clients to communicate on
     let mut bcast_rx = broadcast.subscribe();
     // Track internal client state
     let mut conn = conn::ClientConn::new(client_info.remote_ip);
+    // apply per-connection limits
+    conn.set_max_subs(settings.limits.max_subscriptions);
+    conn.set_max_subid_len(settings.limits.max_subid_length);
+    // limit concurrent database queries for this client
+    conn.set_max_db_queries(settings.limits.db_conns_per_client.unwrap_or(0));
     // subscription creation rate limiting
-    let mut sub_lim_opt = None;
+    let mut sub_lim_opt = subscription_limiter(settings.limits.subscriptions_per_min);
     // 100ms jitter when the rate limiter returns
     let jitter = Jitter::up_to(Duration::from_millis(100));
-    let sub_per_min_setting = settings.limits.subscriptions_per_min;
-    if let Some(sub_per_min) = sub_per_min_setting {
-        if sub_per_min > 0 {
-            trace!("Rate limits for sub creation ({}/min)", sub_per_min);
-            let quota_time = core::num::NonZeroU32::new(sub_per_min).unwrap();
-            let quota = Quota::per_minute(quota_time);
-            sub_lim_opt = Some(RateLimiter::direct(quota));
-        }
-    }
     // Use the remote IP as the client identifier
     let cid = conn.get_client_prefix();
     // Create a channel for receiving query results from the database.
@@ -1098,6 +1348,10 @@ async fn nostr_server(
     // this has capacity for some of the larger requests we see, which
     // should allow the DB thread to release the handle earlier.
     let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(20_000);
+    // Create channel for receiving COUNT results
+    let (count_tx, mut count_rx) = mpsc::channel::<db::CountResult>(128);
+    // Create channel for receiving NEG-OPEN query results
+    let (neg_tx, mut neg_rx) = mpsc::channel::<negentropy::OpenResult>(128);
     // Create channel for receiving NOTICEs
     let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(128);
 
@@ -1115,8 +1369,17 @@ async fn nostr_server(
 
     // maintain a hashmap of a oneshot channel for active subscriptions.
     // when these subscriptions are cancelled, make a message
-    // available to the executing query so it knows to stop.
-    let mut running_queries: HashMap<String, oneshot::Sender<()>> = HashMap::new();
+    // available to the executing query so it knows to stop.  Queries
+    // are numbered, so that results from a cancelled query are not
+    // mistaken for those of a later one with the same id.
+    let mut running_queries: HashMap<String, (u64, oneshot::Sender<()>)> = HashMap::new();
+    let mut query_count: u64 = 0;
+    // open negentropy (NIP-77) sessions, with the events matching
+    // their filter.
+    let mut neg_sessions: HashMap<String, negentropy::Storage> = HashMap::new();
+    // sessions still waiting on their query, by the number of the
+    // query; results for sessions closed in the meantime are dropped.
+    let mut neg_pending: HashMap<String, u64> = HashMap::new();
     // for stats, keep track of how many events the client published,
     // and how many it received from queries.
     let mut client_published_event_count: usize = 0;
@@ -1133,6 +1396,15 @@ async fn nostr_server(
 
     // Measure connections
     metrics.connections.inc();
+    // Track the client, so it can be listed and disconnected.  The
+    // full id is used, since prefixes are not unique.
+    let client_id = conn.get_client_id();
+    let mut disconnect_rx = clients.register(ClientStats {
+        client_id: client_id.clone(),
+        remote_ip: conn.ip().to_owned(),
+        user_agent: client_info.user_agent.clone(),
+        connected_at: unix_time(),
+    });
 
     if settings.authorization.nip42_auth {
         conn.generate_auth_challenge();
@@ -1154,6 +1426,27 @@ async fn nostr_server(
                 // server shutting down, exit loop
                 break;
             },
+            Ok(()) = settings_rx.changed() => {
+                // apply reloaded settings without dropping the connection
+                let new_settings = settings_rx.borrow_and_update().clone();
+                conn.set_max_subs(new_settings.limits.max_subscriptions);
+                conn.set_max_subid_len(new_settings.limits.max_subid_length);
+                if new_settings.limits.db_conns_per_client != settings.limits.db_conns_per_client {
+                    conn.set_max_db_queries(new_settings.limits.db_conns_per_client.unwrap_or(0));
+                }
+                if new_settings.limits.subscriptions_per_min != settings.limits.subscriptions_per_min {
+                    sub_lim_opt = subscription_limiter(new_settings.limits.subscriptions_per_min);
+                }
+                settings = new_settings;
+                debug!("applied reloaded settings (cid: {})", cid);
+            },
+            Ok(disconnect_id) = disconnect_rx.recv() => {
+                if disconnect_id == client_id {
+                    metrics.disconnects.with_label_values(&["control"]).inc();
+                    info!("Close connection as requested by control message, client: {}, ip: {:?}", cid, conn.ip());
+                    break;
+                }
+            },
             _ = ping_interval.tick() => {
                 // check how long since we talked to client
                 // if it has been too long, disconnect
@@ -1168,10 +1461,46 @@ async fn nostr_server(
             Some(notice_msg) = notice_rx.recv() => {
                 ws_stream.send(make_notice_message(&notice_msg)).await.ok();
             },
+            Some(count_result) = count_rx.recv() => {
+                // database informed us of a count we asked for
+                let send_str = json!(["COUNT", count_result.sub_id, {"count": count_result.count}]).to_string();
+                ws_stream.send(Message::Text(send_str)).await.ok();
+            },
+            Some(open_result) = neg_rx.recv() => {
+                // database informed us of a negentropy session we opened
+                if neg_pending.get(&open_result.sub_id) != Some(&open_result.query_id) {
+                    // closed or replaced while the query ran
+                    continue;
+                }
+                neg_pending.remove(&open_result.sub_id);
+                match open_result.result {
+                    Ok((storage, reply)) => {
+                        neg_sessions.insert(open_result.sub_id.clone(), storage);
+                        ws_stream.send(make_neg_message(&open_result.sub_id, &reply)).await.ok();
+                    },
+                    Err(reason) => {
+                        ws_stream.send(make_neg_err_message(&open_result.sub_id, &reason)).await.ok();
+                    }
+                }
+            },
             Some(query_result) = query_rx.recv() => {
                 // database informed us of a query result we asked for
+                let current = running_queries
+                    .get(&query_result.sub_id)
+                    .is_some_and(|(query_id, _)| *query_id == query_result.query_id);
+                if !current {
+                    // the subscription was closed or replaced
+                    continue;
+                }
                 let subesc = query_result.sub_id.replace('"', "");
-                if query_result.event == "EOSE" {
+                if let Some(reason) = query_result.closed {
+                    // the relay ended this subscription; stop
+                    // matching new events against it too.
+                    debug!("subscription closed by relay: {} (cid: {}, sub: {:?})", reason, cid, query_result.sub_id);
+                    running_queries.remove(&query_result.sub_id);
+                    conn.unsubscribe(&Close { id: query_result.sub_id.clone() });
+                    ws_stream.send(Message::Text(json!(["CLOSED", query_result.sub_id, reason]).to_string())).await.ok();
+                } else if query_result.event == "EOSE" {
                     let send_str = format!("[\"EOSE\",\"{subesc}\"]");
                     ws_stream.send(Message::Text(send_str)).await.ok();
                 } else if allowed_to_send(&query_result.event, &conn, &settings) {
@@ -1271,6 +1600,34 @@ async fn nostr_server(
                                 if e.is_expired() {
                                     let notice = Notice::invalid(e.id, "The event has already expired");
                                     ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if settings.limits.max_event_tags.is_some_and(|m| e.tags.len() > m) {
+                                    let notice = Notice::invalid(e.id, "The event has too many tags for this relay.");
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if settings.limits.max_content_length.is_some_and(|m| e.content.chars().count() > m) {
+                                    let notice = Notice::invalid(e.id, "The event content is too long for this relay.");
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if e.is_protected() && conn.auth_pubkey() != Some(&e.pubkey) {
+                                    // protected events (NIP-70) may only be published by their authenticated author
+                                    info!("client: {} sent a protected event without authenticating as its author", cid);
+                                    if !settings.authorization.nip42_auth {
+                                        let notice = Notice::restricted(e.id, "this relay does not accept protected events");
+                                        ws_stream.send(make_notice_message(&notice)).await.ok();
+                                    } else if conn.auth_pubkey().is_some() {
+                                        let notice = Notice::restricted(e.id, "this event may only be published by its author");
+                                        ws_stream.send(make_notice_message(&notice)).await.ok();
+                                    } else {
+                                        let notice = Notice::auth_required(e.id, "this event may only be published by its author");
+                                        ws_stream.send(make_notice_message(&notice)).await.ok();
+                                        if let Some(challenge) = conn.auth_challenge() {
+                                            ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                                        }
+                                    }
+                                } else if e.is_too_old(settings.options.reject_past_seconds) {
+                                    info!("client: {} sent a far past-dated event", cid);
+                                    let past_sec = settings.options.reject_past_seconds.unwrap_or_default();
+                                    let msg = format!("The event created_at field is out of the acceptable range (-{past_sec}sec) for this relay.");
+                                    let notice = Notice::invalid(e.id, &msg);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
                                     // check if the event is too far in the future.
                                 } else if e.is_valid_timestamp(settings.options.reject_future_seconds) {
                                     // Write this to the database.
@@ -1331,8 +1688,13 @@ async fn nostr_server(
                             }
                         }
                     },
-                    Ok(NostrMessage::SubMsg(s)) => {
+                    Ok(NostrMessage::SubMsg(mut s)) => {
                         debug!("subscription requested (cid: {}, sub: {:?})", cid, s.id);
+                        // only indexed tag names can be queried
+                        s.restrict_tags(|t| settings.options.tag_is_indexed(t));
+                        if let Some(max_limit) = settings.limits.max_limit {
+                            s.clamp_limit(max_limit);
+                        }
                         // subscription handling consists of:
                         // * check for rate limits
                         // * registering the subscription so future events can be matched
@@ -1341,6 +1703,12 @@ async fn nostr_server(
                         // Do nothing if the sub already exists.
                         if conn.has_subscription(&s) {
                             info!("client sent duplicate subscription, ignoring (cid: {}, sub: {:?})", cid, s.id);
+                        } else if read_requires_auth(&s, &conn, &settings) {
+                            info!("subscription requires authentication (cid: {}, sub: {:?})", cid, s.id);
+                            ws_stream.send(make_notice_message(&Notice::closed(s.id, "authenticate to read from this relay", EventResultStatus::AuthRequired))).await.ok();
+                            if let Some(challenge) = conn.auth_challenge() {
+                                ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                            }
                         } else {
                             metrics.cmd_req.inc();
                             if let Some(ref lim) = sub_lim_opt {
@@ -1348,28 +1716,201 @@ async fn nostr_server(
                             }
                             if settings.limits.limit_scrapers && s.is_scraper() {
                                 info!("subscription was scraper, ignoring (cid: {}, sub: {:?})", cid, s.id);
-                                ws_stream.send(Message::Text(format!("[\"EOSE\",\"{}\"]", s.id))).await.ok();
+                                ws_stream.send(make_notice_message(&Notice::closed(s.id, "filters are too broad, add ids, authors or tags", EventResultStatus::Blocked))).await.ok();
                                 continue
                             }
+                            // reserve one of the client's database query slots.
+                            let query_permit = if s.needs_historical_events() {
+                                match conn.reserve_db_query() {
+                                    Ok(permit) => permit,
+                                    Err(e) => {
+                                        info!("Subscription rejected: {} (cid: {}, sub: {:?})", e, cid, s.id);
+                                        metrics.query_aborts.with_label_values(&["clientlimit"]).inc();
+                                        ws_stream.send(make_notice_message(&Notice::closed(s.id, &format!("{e}"), sub_error_status(&e)))).await.ok();
+                                        continue;
+                                    }
+                                }
+                            } else {
+                                None
+                            };
                             let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
-                            match conn.subscribe(s.clone()) {
+                            match s.check_limits(settings.limits.max_filters, settings.limits.max_filter_items).and_then(|()| conn.subscribe(s.clone())) {
                                 Ok(()) => {
                                     // when we insert, if there was a previous query running with the same name, cancel it.
-                                    if let Some(previous_query) = running_queries.insert(s.id.clone(), abandon_query_tx) {
+                                    query_count += 1;
+                                    if let Some((_, previous_query)) = running_queries.insert(s.id.clone(), (query_count, abandon_query_tx)) {
                                         previous_query.send(()).ok();
                                     }
                                     if s.needs_historical_events() {
                                         // start a database query.  this spawns a blocking database query on a worker thread.
-                                        repo.query_subscription(s, cid.clone(), query_tx.clone(), abandon_query_rx).await.ok();
+                                        repo.query_subscription(s, query_count, cid.clone(), query_tx.clone(), abandon_query_rx, query_permit).await.ok();
                                     }
                                 },
                                 Err(e) => {
                                     info!("Subscription error: {} (cid: {}, sub: {:?})", e, cid, s.id);
-                                    ws_stream.send(make_notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
+                                    ws_stream.send(make_notice_message(&Notice::closed(s.id, &format!("{e}"), sub_error_status(&e)))).await.ok();
+                                }
+                            }
+                        }
+                    },
+                    Ok(NostrMessage::CountMsg(c)) => {
+                        debug!("count requested (cid: {}, sub: {:?})", cid, c.id);
+                        metrics.cmd_count.inc();
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        let mut sub: Subscription = c.into();
+                        if read_requires_auth(&sub, &conn, &settings) {
+                            info!("count requires authentication (cid: {}, sub: {:?})", cid, sub.id);
+                            ws_stream.send(make_notice_message(&Notice::closed(sub.id, "authenticate to read from this relay", EventResultStatus::AuthRequired))).await.ok();
+                            if let Some(challenge) = conn.auth_challenge() {
+                                ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                            }
+                            continue;
+                        }
+                        // only indexed tag names can be queried
+                        sub.restrict_tags(|t| settings.options.tag_is_indexed(t));
+                        // counts use one of the client's database query slots.
+                        let checked = if sub.id.len() > settings.limits.max_subid_length {
+                            Err(Error::SubIdMaxLengthError)
+                        } else {
+                            sub.check_limits(settings.limits.max_filters, settings.limits.max_filter_items)
+                        };
+                        let query_permit = match checked.and_then(|()| conn.reserve_db_query()) {
+                            Ok(permit) => permit,
+                            Err(e) => {
+                                info!("Count rejected: {} (cid: {}, sub: {:?})", e, cid, sub.id);
+                                if let Error::DbQueryMaxExceededError = e {
+                                    metrics.query_aborts.with_label_values(&["clientlimit"]).inc();
+                                }
+                                ws_stream.send(make_notice_message(&Notice::closed(sub.id, &format!("{e}"), sub_error_status(&e)))).await.ok();
+                                continue;
+                            }
+                        };
+                        // counts are not subscriptions; run the query
+                        // in the background and report the result once.
+                        let repo = repo.clone();
+                        let count_tx = count_tx.clone();
+                        let closed_tx = notice_tx.clone();
+                        let cid = cid.clone();
+                        tokio::task::spawn(async move {
+                            // hold the query permit until the count is done
+                            let _query_permit = query_permit;
+                            let sub_id = sub.get_id();
+                            match repo.count_subscription(sub, cid.clone()).await {
+                                Ok(count) => {
+                                    count_tx.send(db::CountResult { sub_id, count }).await.ok();
+                                },
+                                Err(e) => {
+                                    warn!("count query failed: {:?} (cid: {}, sub: {:?})", e, cid, sub_id);
+                                    closed_tx.send(Notice::closed(sub_id, "count query failed", EventResultStatus::Error)).await.ok();
+                                }
+                            }
+                        });
+                    },
+                    Ok(NostrMessage::NegOpenMsg(n)) => {
+                        debug!("negentropy session requested (cid: {}, sub: {:?})", cid, n.id);
+                        metrics.cmd_neg.inc();
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        // opening an existing session replaces it.
+                        neg_sessions.remove(&n.id);
+                        neg_pending.remove(&n.id);
+                        let mut sub = Subscription { id: n.id, filters: vec![n.filter] };
+                        if read_requires_auth(&sub, &conn, &settings) {
+                            info!("negentropy requires authentication (cid: {}, sub: {:?})", cid, sub.id);
+                            ws_stream.send(make_neg_err_message(&sub.id, "auth-required: authenticate to read from this relay")).await.ok();
+                            if let Some(challenge) = conn.auth_challenge() {
+                                ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                            }
+                            continue;
+                        }
+                        let Ok(initial_msg) = hex::decode(&n.message) else {
+                            ws_stream.send(make_neg_err_message(&sub.id, "invalid: message is not hex")).await.ok();
+                            continue;
+                        };
+                        // only indexed tag names can be queried
+                        sub.restrict_tags(|t| settings.options.tag_is_indexed(t));
+                        // sessions count against the subscription limit,
+                        // and their query uses a database query slot.
+                        let checked = if sub.id.len() > settings.limits.max_subid_length {
+                            Err(Error::SubIdMaxLengthError)
+                        } else if neg_sessions.len() + neg_pending.len() >= settings.limits.max_subscriptions {
+                            Err(Error::SubMaxExceededError)
+                        } else {
+                            sub.check_limits(settings.limits.max_filters, settings.limits.max_filter_items)
+                        };
+                        let query_permit = match checked.and_then(|()| conn.reserve_db_query()) {
+                            Ok(permit) => permit,
+                            Err(e) => {
+                                info!("Negentropy session rejected: {} (cid: {}, sub: {:?})", e, cid, sub.id);
+                                if let Error::DbQueryMaxExceededError = e {
+                                    metrics.query_aborts.with_label_values(&["clientlimit"]).inc();
                                 }
+                                ws_stream.send(make_neg_err_message(&sub.id, &format!("{}: {e}", sub_error_status(&e).prefix()))).await.ok();
+                                continue;
+                            }
+                        };
+                        // gather the matching events in the background,
+                        // and answer the initial message once ready.
+                        query_count += 1;
+                        let query_id = query_count;
+                        neg_pending.insert(sub.id.clone(), query_id);
+                        let repo = repo.clone();
+                        let neg_tx = neg_tx.clone();
+                        let cid = cid.clone();
+                        let max_items = settings.limits.max_negentropy_items;
+                        tokio::task::spawn(async move {
+                            // hold the query permit until the query is done
+                            let _query_permit = query_permit;
+                            let sub_id = sub.get_id();
+                            // one more than the maximum is enough to refuse
+                            // the session, without reading every match.
+                            let limit = max_items.map(|max| max + 1);
+                            let result = match repo.negentropy_items(sub, cid.clone(), limit).await {
+                                Ok(items) => {
+                                    let storage = negentropy::Storage::new(items);
+                                    if max_items.is_some_and(|max| storage.len() > max) {
+                                        Err("blocked: too many query results".to_owned())
+                                    } else {
+                                        storage
+                                            .reconcile(&initial_msg, negentropy::FRAME_SIZE_LIMIT)
+                                            .map(|reply| (storage, hex::encode(reply)))
+                                            .map_err(|e| format!("invalid: {e}"))
+                                    }
+                                },
+                                Err(e) => {
+                                    warn!("negentropy query failed: {:?} (cid: {}, sub: {:?})", e, cid, sub_id);
+                                    Err("error: negentropy query failed".to_owned())
+                                }
+                            };
+                            neg_tx.send(negentropy::OpenResult { sub_id, query_id, result }).await.ok();
+                        });
+                    },
+                    Ok(NostrMessage::NegMsg(n)) => {
+                        let reply = match (neg_sessions.get(&n.id), hex::decode(&n.message)) {
+                            (None, _) => Err("closed: no open negentropy session".to_owned()),
+                            (Some(_), Err(_)) => Err("invalid: message is not hex".to_owned()),
+                            (Some(storage), Ok(msg)) => storage
+                                .reconcile(&msg, negentropy::FRAME_SIZE_LIMIT)
+                                .map_err(|e| format!("invalid: {e}")),
+                        };
+                        match reply {
+                            Ok(reply) => {
+                                ws_stream.send(make_neg_message(&n.id, &hex::encode(reply))).await.ok();
+                            },
+                            Err(reason) => {
+                                // errors end the session
+                                neg_sessions.remove(&n.id);
+                                ws_stream.send(make_neg_err_message(&n.id, &reason)).await.ok();
                             }
                         }
                     },
+                    Ok(NostrMessage::NegCloseMsg(c)) => {
+                        neg_sessions.remove(&c.id);
+                        neg_pending.remove(&c.id);
+                    },
                     Ok(NostrMessage::CloseMsg(cc)) => {
                         // closing a request simply removes the subscription.
                         let parsed : Result<Close> = Result::<Close>::from(cc);
@@ -1378,7 +1919,7 @@ async fn nostr_server(
                             // check if a query is currently
                             // running, and remove it if so.
                             let stop_tx = running_queries.remove(&c.id);
-                            if let Some(tx) = stop_tx {
+                            if let Some((_, tx)) = stop_tx {
                                 tx.send(()).ok();
                             }
                             // stop checking new events against
@@ -1409,9 +1950,10 @@ async fn nostr_server(
         }
     }
     // connection cleanup - ensure any still running queries are terminated.
-    for (_, stop_tx) in running_queries {
+    for (_, (_, stop_tx)) in running_queries {
         stop_tx.send(()).ok();
     }
+    clients.unregister(&client_id);
     info!(
         "stopping client connection (cid: {}, ip: {:?}, sent: {} events, recv: {} events, connected: {:?})",
         cid,
@@ -1436,4 +1978,7 @@ pub struct NostrMetrics {
     pub cmd_event: IntCounter,       // count of EVENT commands received
     pub cmd_close: IntCounter,       // count of CLOSE commands received
     pub cmd_auth: IntCounter,        // count of AUTH commands received
+    pub cmd_count: IntCounter,       // count of COUNT commands received
+    pub cmd_neg: IntCounter,         // count of NEG-OPEN commands received
+    pub purged_events: IntCounter,   // count of hidden events purged
 }
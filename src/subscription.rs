@@ -1,5 +1,5 @@
 //! Subscription and filter parsing
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::event::Event;
 use serde::de::Unexpected;
 use serde::ser::SerializeMap;
@@ -35,7 +35,9 @@ pub struct ReqFilter {
     /// Limit number of results
     pub limit: Option<u64>,
     /// Set of tags
-    pub tags: Option<HashMap<char, HashSet<String>>>,
+    pub tags: Option<HashMap<String, HashSet<String>>>,
+    /// Full-text search of event content (NIP-50)
+    pub search: Option<String>,
     /// Force no matches due to malformed data
     // we can't represent it in the req filter, so we don't want to
     // erroneously match.  This basically indicates the req tried to
@@ -67,6 +69,9 @@ impl Serialize for ReqFilter {
         if let Some(authors) = &self.authors {
             map.serialize_entry("authors", &authors)?;
         }
+        if let Some(search) = &self.search {
+            map.serialize_entry("search", search)?;
+        }
         // serialize tags
         if let Some(tags) = &self.tags {
             for (k, v) in tags {
@@ -98,6 +103,7 @@ impl<'de> Deserialize<'de> for ReqFilter {
             authors: None,
             limit: None,
             tags: None,
+            search: None,
             force_no_match: false,
         };
         let empty_string = "".into();
@@ -124,6 +130,8 @@ impl<'de> Deserialize<'de> for ReqFilter {
                 rf.until = Deserialize::deserialize(val).ok();
             } else if key == "limit" {
                 rf.limit = Deserialize::deserialize(val).ok();
+            } else if key == "search" {
+                rf.search = Deserialize::deserialize(val).ok();
             } else if key == "authors" {
                 let raw_authors: Option<Vec<String>> = Deserialize::deserialize(val).ok();
                 if let Some(a) = raw_authors.as_ref() {
@@ -136,23 +144,18 @@ impl<'de> Deserialize<'de> for ReqFilter {
                 }
                 rf.authors = raw_authors;
             } else if key.starts_with('#') && key.len() > 1 && val.is_array() {
-                if let Some(tag_search) = tag_search_char_from_filter(key) {
-                    if ts.is_none() {
-                        // Initialize the tag if necessary
-                        ts = Some(HashMap::new());
-                    }
-                    if let Some(m) = ts.as_mut() {
-                        let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
-                        if let Some(v) = tag_vals {
-                            let hs = v.into_iter().collect::<HashSet<_>>();
-                            m.insert(tag_search.to_owned(), hs);
-                        }
-                    };
-                } else {
-                    // tag search that is multi-character, don't add to subscription
-                    rf.force_no_match = true;
-                    continue;
+                let tag_search = &key[1..];
+                if ts.is_none() {
+                    // Initialize the tag if necessary
+                    ts = Some(HashMap::new());
                 }
+                if let Some(m) = ts.as_mut() {
+                    let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
+                    if let Some(v) = tag_vals {
+                        let hs = v.into_iter().collect::<HashSet<_>>();
+                        m.insert(tag_search.to_owned(), hs);
+                    }
+                };
             }
         }
         rf.tags = ts;
@@ -160,24 +163,49 @@ impl<'de> Deserialize<'de> for ReqFilter {
     }
 }
 
-/// Attempt to form a single-char identifier from a tag search filter
-fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
-    let tagname_nohash = &tagname[1..];
-    // We return the tag character if and only if the tagname consists
-    // of a single char.
-    let mut tagnamechars = tagname_nohash.chars();
-    let firstchar = tagnamechars.next();
-    match firstchar {
-        Some(_) => {
-            // check second char
-            if tagnamechars.next().is_none() {
-                firstchar
-            } else {
-                None
-            }
-        }
-        None => None,
-    }
+/// Parse a `[<cmd>, <sub_id>, <filter>...]` array into a
+/// subscription identifier and deduplicated filter list.
+fn parse_filter_msg<E: serde::de::Error>(
+    mut v: Value,
+    cmd: &str,
+) -> Result<(String, Vec<ReqFilter>), E> {
+    // this should be a 3-or-more element array.
+    // verify the first element is a String matching the command
+    // get the subscription from the second element.
+    // convert each of the remaining objects into filters
+
+    // check for array
+    let va = v.as_array_mut().ok_or_else(|| E::custom("not array"))?;
+
+    // check length
+    if va.len() < 3 {
+        return Err(E::custom("not enough fields"));
+    }
+    let mut i = va.iter_mut();
+    // get command ("REQ"/"COUNT") and ensure it is a string
+    let req_cmd_str: serde_json::Value = i.next().unwrap().take();
+    let req = req_cmd_str
+        .as_str()
+        .ok_or_else(|| E::custom("first element of request was not a string"))?;
+    if req != cmd {
+        return Err(E::custom(format!("missing {cmd} command")));
+    }
+
+    // ensure sub id is a string
+    let sub_id_str: serde_json::Value = i.next().unwrap().take();
+    let sub_id = sub_id_str
+        .as_str()
+        .ok_or_else(|| E::custom("missing subscription id"))?;
+
+    let mut filters = vec![];
+    for fv in i {
+        let f: ReqFilter = serde_json::from_value(fv.take())
+            .map_err(|_| E::custom("could not parse filter"))?;
+        // create indexes
+        filters.push(f);
+    }
+    filters.dedup();
+    Ok((sub_id.to_owned(), filters))
 }
 
 impl<'de> Deserialize<'de> for Subscription {
@@ -187,53 +215,86 @@ impl<'de> Deserialize<'de> for Subscription {
         where
             D: Deserializer<'de>,
     {
-        let mut v: Value = Deserialize::deserialize(deserializer)?;
-        // this should be a 3-or-more element array.
-        // verify the first element is a String, REQ
-        // get the subscription from the second element.
-        // convert each of the remaining objects into filters
-
-        // check for array
-        let va = v
-            .as_array_mut()
-            .ok_or_else(|| serde::de::Error::custom("not array"))?;
-
-        // check length
-        if va.len() < 3 {
-            return Err(serde::de::Error::custom("not enough fields"));
-        }
-        let mut i = va.iter_mut();
-        // get command ("REQ") and ensure it is a string
-        let req_cmd_str: serde_json::Value = i.next().unwrap().take();
-        let req = req_cmd_str
-            .as_str()
-            .ok_or_else(|| serde::de::Error::custom("first element of request was not a string"))?;
-        if req != "REQ" {
-            return Err(serde::de::Error::custom("missing REQ command"));
-        }
+        let v: Value = Deserialize::deserialize(deserializer)?;
+        let (id, filters) = parse_filter_msg(v, "REQ")?;
+        Ok(Subscription { id, filters })
+    }
+}
 
-        // ensure sub id is a string
-        let sub_id_str: serde_json::Value = i.next().unwrap().take();
-        let sub_id = sub_id_str
-            .as_str()
-            .ok_or_else(|| serde::de::Error::custom("missing subscription id"))?;
-
-        let mut filters = vec![];
-        for fv in i {
-            let f: ReqFilter = serde_json::from_value(fv.take())
-                .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
-            // create indexes
-            filters.push(f);
+/// Count request (NIP-45) identifier and set of request filters
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct Count {
+    pub id: String,
+    pub filters: Vec<ReqFilter>,
+}
+
+impl<'de> Deserialize<'de> for Count {
+    /// Custom deserializer for count requests, which share the
+    /// structure of a `REQ`.
+    fn deserialize<D>(deserializer: D) -> Result<Count, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let v: Value = Deserialize::deserialize(deserializer)?;
+        let (id, filters) = parse_filter_msg(v, "COUNT")?;
+        Ok(Count { id, filters })
+    }
+}
+
+impl From<Count> for Subscription {
+    fn from(c: Count) -> Subscription {
+        Subscription {
+            id: c.id,
+            filters: c.filters,
         }
-        filters.dedup();
-        Ok(Subscription {
-            id: sub_id.to_owned(),
-            filters,
-        })
     }
 }
 
 impl Subscription {
+    /// Restrict tag queries to the tag names accepted by
+    /// `is_indexed`.  Filters that query any other tag name are
+    /// forced to never match, since those tags are not stored.
+    pub fn restrict_tags<F: Fn(&str) -> bool>(&mut self, is_indexed: F) {
+        for f in &mut self.filters {
+            if let Some(map) = &f.tags {
+                if map.keys().any(|k| !is_indexed(k)) {
+                    f.force_no_match = true;
+                }
+            }
+        }
+    }
+
+    /// Check the number of filters, and the number of values in any
+    /// single filter field, against the relay limits.
+    /// # Errors
+    ///
+    /// Will return `Err` if either limit is exceeded.
+    pub fn check_limits(
+        &self,
+        max_filters: Option<usize>,
+        max_filter_items: Option<usize>,
+    ) -> Result<()> {
+        if let Some(max) = max_filters {
+            if self.filters.len() > max {
+                return Err(Error::FilterMaxExceededError);
+            }
+        }
+        if let Some(max) = max_filter_items {
+            if self.filters.iter().any(|f| f.max_item_count() > max) {
+                return Err(Error::FilterItemsMaxExceededError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reduce any filter `limit` larger than `max_limit`, and use
+    /// `max_limit` for filters without one.
+    pub fn clamp_limit(&mut self, max_limit: u64) {
+        for f in &mut self.filters {
+            f.limit = Some(f.limit.map_or(max_limit, |lim| lim.min(max_limit)));
+        }
+    }
+
     /// Get a copy of the subscription identifier.
     #[must_use]
     pub fn get_id(&self) -> String {
@@ -247,6 +308,17 @@ impl Subscription {
         self.filters.iter().any(|f| f.limit != Some(0))
     }
 
+    /// Could this subscription return events of any of these kinds?
+    /// Filters without a kind restriction match every kind.
+    #[must_use]
+    pub fn may_match_kinds(&self, kinds: &[u64]) -> bool {
+        self.filters.iter().any(|f| {
+            f.kinds
+                .as_ref()
+                .is_none_or(|ks| ks.iter().any(|k| kinds.contains(k)))
+        })
+    }
+
     /// Determine if this subscription matches a given [`Event`].  Any
     /// individual filter match is sufficient.
     #[must_use]
@@ -294,6 +366,24 @@ fn prefix_match(prefixes: &[String], target: &str) -> bool {
 }
 
 impl ReqFilter {
+    /// Largest number of values in any one field of this filter.
+    fn max_item_count(&self) -> usize {
+        let tag_counts = self
+            .tags
+            .iter()
+            .flat_map(|m| m.values())
+            .map(HashSet::len);
+        [
+            self.ids.as_ref().map_or(0, Vec::len),
+            self.authors.as_ref().map_or(0, Vec::len),
+            self.kinds.as_ref().map_or(0, Vec::len),
+        ]
+        .into_iter()
+        .chain(tag_counts)
+        .max()
+        .unwrap_or(0)
+    }
+
     fn ids_match(&self, event: &Event) -> bool {
         self.ids
             .as_ref()
@@ -320,7 +410,7 @@ impl ReqFilter {
         // get the hashset from the filter.
         if let Some(map) = &self.tags {
             for (key, val) in map.iter() {
-                let tag_match = event.generic_tag_val_intersect(*key, val);
+                let tag_match = event.generic_tag_val_intersect(key, val);
                 // if there is no match for this tag, the match fails.
                 if !tag_match {
                     return false;
@@ -332,6 +422,17 @@ impl ReqFilter {
         true
     }
 
+    /// Check if every search term is a word of the event content,
+    /// ignoring case.
+    fn search_match(&self, event: &Event) -> bool {
+        if let Some(search) = &self.search {
+            let words: HashSet<String> = search_terms(&event.content).into_iter().collect();
+            search_terms(search).iter().all(|term| words.contains(term))
+        } else {
+            true
+        }
+    }
+
     /// Check if this filter either matches, or does not care about the kind.
     fn kind_match(&self, kind: u64) -> bool {
         self.kinds.as_ref().map_or(true, |ks| ks.contains(&kind))
@@ -347,10 +448,21 @@ impl ReqFilter {
             && self.kind_match(event.kind)
             && (self.authors_match(event) || self.delegated_authors_match(event))
             && self.tag_match(event)
+            && self.search_match(event)
             && !self.force_no_match
     }
 }
 
+/// Split text into lowercase words, as the full-text indexes do; any
+/// character that is not a letter or digit separates words.
+#[must_use]
+pub fn search_terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -365,6 +477,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn count_request_parse() -> Result<()> {
+        let raw_json = r#"["COUNT","some-id",{"kinds":[1]},{"kinds":[1]}]"#;
+        let c: Count = serde_json::from_str(raw_json)?;
+        assert_eq!(c.id, "some-id");
+        assert_eq!(c.filters.len(), 1);
+        assert!(serde_json::from_str::<Subscription>(raw_json).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn count_missing_filters() {
+        let raw_json = "[\"COUNT\",\"some-id\"]";
+        assert!(serde_json::from_str::<Count>(raw_json).is_err());
+    }
+
     #[test]
     fn incorrect_header() {
         let raw_json = "[\"REQUEST\",\"some-id\",\"{}\"]";
@@ -594,6 +722,70 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn interest_search() -> Result<()> {
+        // subscription with a full-text search
+        let s: Subscription =
+            serde_json::from_str(r#"["REQ","xyz",{"search":"lot-2024 PALLET"}]"#)?;
+        let mut e = Event {
+            id: "abc".to_owned(),
+            pubkey: "".to_owned(),
+            delegated_by: None,
+            created_at: 0,
+            kind: 1,
+            tags: Vec::new(),
+            content: "Pallet received, LOT-2024-0042".to_owned(),
+            sig: "".to_owned(),
+            tagidx: None,
+        };
+        assert!(s.interested_in_event(&e));
+        e.content = "Pallet received".to_owned();
+        assert!(!s.interested_in_event(&e));
+        // terms match whole words only
+        e.content = "Pallets received, slot-2024".to_owned();
+        assert!(!s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn multichar_tag_restricted() -> Result<()> {
+        let mut s: Subscription =
+            serde_json::from_str(r##"["REQ","xyz",{"#lot":["A-1042"]},{"#sku":["X"]}]"##)?;
+        let mut e = Event::simple_event();
+        e.tags = vec![vec!["lot".to_owned(), "A-1042".to_owned()]];
+        e.build_index();
+        assert!(s.interested_in_event(&e));
+        s.restrict_tags(|t| t.len() == 1 || t == "sku");
+        assert!(s.filters[0].force_no_match);
+        assert!(!s.filters[1].force_no_match);
+        assert!(!s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn filter_limits() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r##"["REQ","xyz",{"authors":["aa","bb","cc"],"limit":5000},{"#p":["aa","bb"],"limit":10}]"##,
+        )?;
+        assert!(s.check_limits(Some(2), Some(3)).is_ok());
+        assert!(matches!(
+            s.check_limits(Some(1), None),
+            Err(Error::FilterMaxExceededError)
+        ));
+        assert!(matches!(
+            s.check_limits(None, Some(2)),
+            Err(Error::FilterItemsMaxExceededError)
+        ));
+        s.clamp_limit(500);
+        assert_eq!(s.filters[0].limit, Some(500));
+        assert_eq!(s.filters[1].limit, Some(10));
+        // filters without a limit get the maximum
+        let mut s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"kinds":[1]}]"#)?;
+        s.clamp_limit(500);
+        assert_eq!(s.filters[0].limit, Some(500));
+        Ok(())
+    }
+
     #[test]
     fn authors_single() -> Result<()> {
         // subscription with a filter for ID
@@ -671,6 +863,16 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn kind_scoped_match() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"kinds":[1,7]}]"#)?;
+        assert!(s.may_match_kinds(&[7, 30078]));
+        assert!(!s.may_match_kinds(&[30078]));
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"kinds":[1]},{"authors":["abc"]}]"#)?;
+        assert!(s.may_match_kinds(&[30078]));
+        Ok(())
+    }
+
     #[test]
     fn is_scraper() -> Result<()> {
         assert!(serde_json::from_str::<Subscription>(r#"["REQ","some-id",{"kinds": [1984],"since": 123,"limit":1}]"#)?.is_scraper());
@@ -1,8 +1,14 @@
 use anyhow::{anyhow, Result};
+use bitcoin_hashes::{sha256, Hash};
 use nostr_rs_relay::config;
+use nostr_rs_relay::control::ControlMessage;
+use nostr_rs_relay::event::Event;
 use nostr_rs_relay::server::start_server;
+use secp256k1::{KeyPair, Message, Secp256k1, XOnlyPublicKey};
 //use http::{Request, Response};
+use futures::StreamExt;
 use hyper::{Client, StatusCode, Uri};
+use serde_json::Value;
 use std::net::TcpListener;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::mpsc as syncmpsc;
@@ -10,20 +16,26 @@ use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tracing::{debug, info};
 
 pub struct Relay {
     pub port: u16,
     pub handle: JoinHandle<()>,
-    pub shutdown_tx: MpscSender<()>,
+    pub shutdown_tx: MpscSender<ControlMessage>,
 }
 
 pub fn start_relay() -> Result<Relay> {
+    start_relay_with(config::Settings::default())
+}
+
+/// Start a relay, overriding the network and database settings from
+/// the ones given.
+pub fn start_relay_with(mut settings: config::Settings) -> Result<Relay> {
     // setup tracing
     let _trace_sub = tracing_subscriber::fmt::try_init();
     info!("Starting a new relay");
-    // replace default settings
-    let mut settings = config::Settings::default();
     // identify open port
     info!("Checking for address...");
     let port = get_available_port().unwrap();
@@ -35,7 +47,8 @@ pub fn start_relay() -> Result<Relay> {
     settings.database.in_memory = true;
     settings.database.min_conn = 4;
     settings.database.max_conn = 8;
-    let (shutdown_tx, shutdown_rx): (MpscSender<()>, MpscReceiver<()>) = syncmpsc::channel();
+    let (shutdown_tx, shutdown_rx): (MpscSender<ControlMessage>, MpscReceiver<ControlMessage>) =
+        syncmpsc::channel();
     let handle = thread::spawn(move || {
         // server will block the thread it is run on.
         let _ = start_server(&settings, shutdown_rx);
@@ -93,7 +106,7 @@ pub async fn wait_for_healthy_relay(relay: &Relay) -> Result<()> {
 
 static PORT_COUNTER: AtomicU16 = AtomicU16::new(4030);
 
-fn get_available_port() -> Option<u16> {
+pub fn get_available_port() -> Option<u16> {
     let startsearch = PORT_COUNTER.fetch_add(10, Ordering::SeqCst);
     if startsearch >= 20000 {
         // wrap around
@@ -105,3 +118,78 @@ pub fn port_is_available(port: u16) -> bool {
     info!("checking on port {}", port);
     TcpListener::bind(("127.0.0.1", port)).is_ok()
 }
+
+/// Generate a new keypair for signing test events.
+pub fn new_keys() -> KeyPair {
+    KeyPair::new(&Secp256k1::new(), &mut secp256k1::rand::thread_rng())
+}
+
+/// Hex-encoded public key for a keypair.
+pub fn pubkey_hex(keys: &KeyPair) -> String {
+    XOnlyPublicKey::from_keypair(keys).to_string()
+}
+
+/// Schnorr-sign the SHA256 digest of a string.
+fn sign_digest(keys: &KeyPair, data: &str) -> (String, String) {
+    let digest: sha256::Hash = sha256::Hash::hash(data.as_bytes());
+    let msg = Message::from_slice(digest.as_ref()).unwrap();
+    let sig = Secp256k1::new().sign_schnorr(&msg, keys);
+    (format!("{digest:x}"), sig.to_string())
+}
+
+/// Create a NIP-26 delegation tag, allowing the delegatee to publish
+/// events matching the conditions on behalf of the delegator.
+pub fn delegation_tag(delegator: &KeyPair, delegatee: &KeyPair, conditions: &str) -> Vec<String> {
+    let token = format!("nostr:delegation:{}:{}", pubkey_hex(delegatee), conditions);
+    let (_, sig) = sign_digest(delegator, &token);
+    vec![
+        "delegation".to_owned(),
+        pubkey_hex(delegator),
+        conditions.to_owned(),
+        sig,
+    ]
+}
+
+/// Create a signed event.
+pub fn signed_event(keys: &KeyPair, kind: u64, tags: Vec<Vec<String>>, content: &str) -> Event {
+    let now = nostr_rs_relay::utils::unix_time();
+    signed_event_at(keys, now, kind, tags, content)
+}
+
+/// Create a signed event with a specific timestamp.
+pub fn signed_event_at(
+    keys: &KeyPair,
+    created_at: u64,
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: &str,
+) -> Event {
+    let mut event = Event {
+        id: "0".to_owned(),
+        pubkey: pubkey_hex(keys),
+        delegated_by: None,
+        created_at,
+        kind,
+        tags,
+        content: content.to_owned(),
+        sig: "0".to_owned(),
+        tagidx: None,
+    };
+    let (id, sig) = sign_digest(keys, &event.to_canonical().unwrap());
+    event.id = id;
+    event.sig = sig;
+    event
+}
+
+/// Read the next text message from a relay as JSON, waiting at most
+/// a few seconds.
+pub async fn next_json(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<Value> {
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await?
+            .ok_or_else(|| anyhow!("connection closed"))??;
+        if msg.is_text() {
+            return Ok(serde_json::from_str(msg.to_text()?)?);
+        }
+    }
+}
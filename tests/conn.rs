@@ -280,6 +280,25 @@ mod tests {
         assert!(matches!(result, Err(Error::AuthFailure)));
     }
 
+    #[test]
+    fn test_db_query_limit() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        // unlimited by default
+        assert!(matches!(client_conn.reserve_db_query(), Ok(None)));
+
+        client_conn.set_max_db_queries(1);
+        let permit = client_conn.reserve_db_query();
+        assert!(matches!(permit, Ok(Some(_))));
+        assert!(matches!(
+            client_conn.reserve_db_query(),
+            Err(Error::DbQueryMaxExceededError)
+        ));
+
+        // finishing the query frees the slot
+        drop(permit);
+        assert!(matches!(client_conn.reserve_db_query(), Ok(Some(_))));
+    }
+
     fn auth_event(challenge: &String) -> Event {
         create_auth_event(Some(challenge), Some(&RELAY.into()), 22242, unix_time())
     }
@@ -1,8 +1,14 @@
 use anyhow::Result;
 use futures::SinkExt;
 use futures::StreamExt;
+use nostr_rs_relay::config;
+use nostr_rs_relay::control::ControlMessage;
+use serde_json::json;
+use std::os::unix::fs::PermissionsExt;
 use std::thread;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
 use tokio_tungstenite::connect_async;
 use tracing::info;
 mod common;
@@ -20,7 +26,7 @@ async fn start_and_stop() -> Result<()> {
     // we will get a SendError.  Keep sending until someone is
     // listening.
     loop {
-        let shutdown_res = relay.shutdown_tx.send(());
+        let shutdown_res = relay.shutdown_tx.send(ControlMessage::Shutdown);
         match shutdown_res {
             Ok(()) => {
                 break;
@@ -44,7 +50,141 @@ async fn relay_home_page() -> Result<()> {
     let relay = common::start_relay()?;
     common::wait_for_healthy_relay(&relay).await?;
     // tell relay to shutdown
-    let _res = relay.shutdown_tx.send(());
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delegated_event_found_by_delegator() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    // a device key publishes with a delegation from the company key
+    let company = common::new_keys();
+    let device = common::new_keys();
+    let tag = common::delegation_tag(&company, &device, "kind=1");
+    let event = common::signed_event(&device, 1, vec![tag], "pallet 7 received");
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    ws.send(json!(["EVENT", event]).to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!(["OK", event.id, true, ""])
+    );
+    // the event is returned when querying for the delegator
+    let req = json!(["REQ", "company", {"authors": [common::pubkey_hex(&company)]}]);
+    ws.send(req.to_string().into()).await?;
+    let received = common::next_json(&mut ws).await?;
+    assert_eq!(received[0], "EVENT");
+    assert_eq!(received[2]["id"], event.id);
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!(["EOSE", "company"])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reloaded_delegation_revocation_hides_events() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let company = common::new_keys();
+    let device = common::new_keys();
+    let tag = common::delegation_tag(&company, &device, "kind=1");
+    let event = common::signed_event(&device, 1, vec![tag], "pallet 7 received");
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    ws.send(json!(["EVENT", event]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    // the device key leaked; revoke it without restarting
+    let mut settings = config::Settings::default();
+    settings.authorization.revoked_delegations = Some(vec![config::RevokedDelegation {
+        delegator: common::pubkey_hex(&company),
+        delegatee: common::pubkey_hex(&device),
+    }]);
+    relay
+        .shutdown_tx
+        .send(ControlMessage::ReloadSettings(Box::new(settings)))?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let req = json!(["REQ", "company", {"authors": [common::pubkey_hex(&company)]}]);
+    ws.send(req.to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!(["EOSE", "company"])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn whitelisted_delegator_can_delegate() -> Result<()> {
+    let company = common::new_keys();
+    let device = common::new_keys();
+    let mut settings = config::Settings::default();
+    settings.authorization.pubkey_whitelist = Some(vec![common::pubkey_hex(&company)]);
+    let relay = common::start_relay_with(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    // the device key is not whitelisted on its own
+    let event = common::signed_event(&device, 1, vec![], "pallet 7 received");
+    ws.send(json!(["EVENT", event]).to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!([
+            "OK",
+            event.id,
+            false,
+            "blocked: pubkey is not allowed to publish to this relay"
+        ])
+    );
+    // a delegation that does not permit this kind is not honored
+    let tag = common::delegation_tag(&company, &device, "kind=1");
+    let event = common::signed_event(&device, 7, vec![tag.clone()], "+");
+    ws.send(json!(["EVENT", event]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], false);
+    // publishing on behalf of the company is allowed
+    let event = common::signed_event(&device, 1, vec![tag], "pallet 7 received");
+    ws.send(json!(["EVENT", event]).to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!(["OK", event.id, true, ""])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn address_deletion_hides_replaceable_event() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let keys = common::new_keys();
+    let author = common::pubkey_hex(&keys);
+    let now = nostr_rs_relay::utils::unix_time();
+    let d_tag = vec!["d".to_owned(), "spec-1".to_owned()];
+    let record = common::signed_event_at(&keys, now - 10, 30023, vec![d_tag.clone()], "v1");
+    ws.send(json!(["EVENT", record]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    // delete the record by its address
+    let addr = vec!["a".to_owned(), format!("30023:{author}:spec-1")];
+    let deletion = common::signed_event_at(&keys, now, 5, vec![addr], "");
+    ws.send(json!(["EVENT", deletion]).to_string().into())
+        .await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    // a re-publication from before the deletion stays hidden
+    let record = common::signed_event_at(&keys, now - 5, 30023, vec![d_tag.clone()], "v2");
+    ws.send(json!(["EVENT", record]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    let req = json!(["REQ", "specs", {"authors": [author], "kinds": [30023]}]);
+    ws.send(req.to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?, json!(["EOSE", "specs"]));
+    // a newer publication is visible again
+    let record = common::signed_event_at(&keys, now + 5, 30023, vec![d_tag], "v3");
+    ws.send(json!(["EVENT", record]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    let req = json!(["REQ", "specs-2", {"authors": [author], "kinds": [30023]}]);
+    ws.send(req.to_string().into()).await?;
+    let received = common::next_json(&mut ws).await?;
+    assert_eq!(received[2]["id"], record.id);
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
     Ok(())
 }
 
@@ -74,6 +214,286 @@ async fn publish_test() -> Result<()> {
     sub_ws.send(event_sub.into()).await?;
     // read from subscription
     let _ws_next = sub_ws.next().await;
-    let _res = relay.shutdown_tx.send(());
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn vanish_request_deletes_author_events() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let keys = common::new_keys();
+    let author = common::pubkey_hex(&keys);
+    let now = nostr_rs_relay::utils::unix_time();
+    let note = common::signed_event_at(&keys, now - 10, 1, vec![], "hello");
+    ws.send(json!(["EVENT", note]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    // ask every relay to forget this pubkey
+    let relay_tag = vec!["relay".to_owned(), "ALL_RELAYS".to_owned()];
+    let vanish = common::signed_event_at(&keys, now, 62, vec![relay_tag], "");
+    ws.send(json!(["EVENT", vanish]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    let req = json!(["REQ", "notes", {"authors": [author], "kinds": [1]}]);
+    ws.send(req.to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?, json!(["EOSE", "notes"]));
+    // older events cannot be published again
+    ws.send(json!(["EVENT", note]).to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!([
+            "OK",
+            note.id,
+            false,
+            "blocked: pubkey has requested to vanish from this relay"
+        ])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn control_message_disconnects_client() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    // make sure the connection is established before asking for stats
+    ws.send(json!(["REQ", "sub", {"limit": 1}]).to_string().into())
+        .await?;
+    assert_eq!(common::next_json(&mut ws).await?, json!(["EOSE", "sub"]));
+    let (stats_tx, stats_rx) = tokio::sync::oneshot::channel();
+    relay
+        .shutdown_tx
+        .send(ControlMessage::DumpStats(stats_tx))?;
+    let stats = stats_rx.await?;
+    assert_eq!(stats.clients.len(), 1);
+    assert_eq!(stats.subscriptions_received, 1);
+    // the relay closes the connection when asked to
+    let client_id = stats.clients[0].client_id.clone();
+    relay
+        .shutdown_tx
+        .send(ControlMessage::DisconnectClient(client_id))?;
+    assert!(ws.next().await.is_none_or(|m| m.is_err()));
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reloaded_settings_apply_to_open_connections() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let keys = common::new_keys();
+    let note = common::signed_event(&keys, 1, vec![], "before reload");
+    ws.send(json!(["EVENT", note]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    // block text notes, without reconnecting
+    let mut settings = config::Settings::default();
+    settings.limits.event_kind_blacklist = Some(vec![1]);
+    relay
+        .shutdown_tx
+        .send(ControlMessage::ReloadSettings(Box::new(settings)))?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let note = common::signed_event(&keys, 1, vec![], "after reload");
+    ws.send(json!(["EVENT", note]).to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!([
+            "OK",
+            note.id,
+            false,
+            "blocked: event kind is blocked by relay"
+        ])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_endpoints_only_on_admin_listener() -> Result<()> {
+    let public_port = common::get_available_port().unwrap();
+    let admin_port = common::get_available_port().unwrap();
+    let socket = std::env::temp_dir().join(format!("nostr-relay-{}.sock", std::process::id()));
+    let socket_path = socket.to_string_lossy().into_owned();
+    let mut settings = config::Settings::default();
+    settings.network.remote_ip_header = Some("x-forwarded-for".to_owned());
+    settings.network.listeners = Some(vec![
+        config::Listener {
+            address: Some(format!("127.0.0.1:{public_port}")),
+            ..Default::default()
+        },
+        config::Listener {
+            unix_socket: Some(socket_path),
+            socket_mode: Some("660".to_owned()),
+            ..Default::default()
+        },
+        config::Listener {
+            address: Some(format!("127.0.0.1:{admin_port}")),
+            admin: true,
+            ..Default::default()
+        },
+    ]);
+    let mut relay = common::start_relay_with(settings)?;
+    // the listeners replace the address and port set for the test
+    relay.port = public_port;
+    common::wait_for_healthy_relay(&relay).await?;
+    let client = hyper::Client::new();
+    let status = |port: u16, path: &str| {
+        let uri: hyper::Uri = format!("http://127.0.0.1:{port}{path}").parse().unwrap();
+        client.get(uri)
+    };
+    assert_eq!(status(public_port, "/metrics").await?.status(), 404);
+    assert_eq!(status(admin_port, "/metrics").await?.status(), 200);
+    assert_eq!(status(admin_port, "/").await?.status(), 404);
+    // the relay is also served on the unix socket
+    let mode = std::fs::metadata(&socket)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+    let mut stream = UnixStream::connect(&socket).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: relay\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_over_pubkey_limit_are_rate_limited() -> Result<()> {
+    let mut settings = config::Settings::default();
+    // allows a burst of 60 events
+    settings.limits.messages_per_sec_per_pubkey = Some(1);
+    let relay = common::start_relay_with(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let keys = common::new_keys();
+    let mut accepted = 0;
+    let (note, ok) = loop {
+        let note = common::signed_event(&keys, 1, vec![], &format!("note {accepted}"));
+        ws.send(json!(["EVENT", note]).to_string().into()).await?;
+        let ok = common::next_json(&mut ws).await?;
+        if ok[2] == false {
+            break (note, ok);
+        }
+        accepted += 1;
+        // the quota refills while we publish, but much slower
+        assert!(accepted < 100, "events were never rate limited");
+    };
+    assert!(accepted >= 60);
+    assert_eq!(
+        ok,
+        json!([
+            "OK",
+            note.id,
+            false,
+            "rate-limited: slow down, too many events published"
+        ])
+    );
+    // other authors are not affected
+    let note = common::signed_event(&common::new_keys(), 1, vec![], "hello");
+    ws.send(json!(["EVENT", note]).to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?[2], true);
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_over_relay_limit_are_rate_limited() -> Result<()> {
+    let mut settings = config::Settings::default();
+    // allows a burst of 60 events, from any author
+    settings.limits.messages_per_sec = Some(1);
+    let relay = common::start_relay_with(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let mut accepted = 0;
+    let (note, ok) = loop {
+        let note = common::signed_event(&common::new_keys(), 1, vec![], "hello");
+        ws.send(json!(["EVENT", note]).to_string().into()).await?;
+        let ok = common::next_json(&mut ws).await?;
+        if ok[2] == false {
+            break (note, ok);
+        }
+        accepted += 1;
+        assert!(accepted < 100, "events were never rate limited");
+    };
+    assert!(accepted >= 60);
+    assert_eq!(
+        ok,
+        json!([
+            "OK",
+            note.id,
+            false,
+            "rate-limited: slow down, too many events published"
+        ])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn negentropy_session_open_message_close() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_negentropy_items = Some(2);
+    let relay = common::start_relay_with(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let keys = common::new_keys();
+    let author = common::pubkey_hex(&keys);
+    let now = nostr_rs_relay::utils::unix_time();
+    let older = common::signed_event_at(&keys, now - 20, 1, vec![], "older");
+    let newer = common::signed_event_at(&keys, now - 10, 1, vec![], "newer");
+    let reaction = common::signed_event_at(&keys, now, 7, vec![], "+");
+    for event in [&older, &newer, &reaction] {
+        ws.send(json!(["EVENT", event]).to_string().into()).await?;
+        assert_eq!(common::next_json(&mut ws).await?[2], true);
+    }
+    // an empty id list for the whole range; the relay replies with
+    // the ids it has, oldest first.
+    let initial = "6100000200";
+    let reply = format!("6100000202{}{}", older.id, newer.id);
+    let notes = json!({"authors": [author], "kinds": [1]});
+    let open = json!(["NEG-OPEN", "neg", notes, initial]);
+    ws.send(open.to_string().into()).await?;
+    let expected = json!(["NEG-MSG", "neg", reply]);
+    assert_eq!(common::next_json(&mut ws).await?, expected);
+    let msg = json!(["NEG-MSG", "neg", initial]);
+    ws.send(msg.to_string().into()).await?;
+    assert_eq!(common::next_json(&mut ws).await?, expected);
+    let close = json!(["NEG-CLOSE", "neg"]);
+    ws.send(close.to_string().into()).await?;
+    ws.send(msg.to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!(["NEG-ERR", "neg", "closed: no open negentropy session"])
+    );
+    // sessions matching more events than allowed are refused
+    let open = json!(["NEG-OPEN", "all", {"authors": [author]}, initial]);
+    ws.send(open.to_string().into()).await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!(["NEG-ERR", "all", "blocked: too many query results"])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
+    Ok(())
+}
+
+#[tokio::test]
+async fn count_with_long_subscription_id_is_closed() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
+    let sub_id = "c".repeat(257);
+    ws.send(json!(["COUNT", sub_id, {"kinds": [1]}]).to_string().into())
+        .await?;
+    assert_eq!(
+        common::next_json(&mut ws).await?,
+        json!([
+            "CLOSED",
+            sub_id,
+            "invalid: Subscription identifier max length exceeded"
+        ])
+    );
+    let _res = relay.shutdown_tx.send(ControlMessage::Shutdown);
     Ok(())
 }